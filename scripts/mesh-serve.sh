@@ -14,8 +14,9 @@ port = 8091
 
 [auth]
 enabled = true
-address = "0.0.0.0"
+address = "127.0.0.1"
 port = 8092
+loopback_only = true
 
 [dstack]
 gateway_domain = "${DSTACK_GATEWAY_DOMAIN}"
@@ -24,38 +25,21 @@ gateway_domain = "${DSTACK_GATEWAY_DOMAIN}"
 cert_file = "/etc/ssl/certs/server.crt"
 key_file = "/etc/ssl/private/server.key"
 ca_file = "/etc/ssl/certs/ca.crt"
-EOF
 
-echo "Generating server certificate using dstack.sock HTTP API..."
-CERT_URL='http://localhost/GetTlsKey?subject=localhost&usage_server_auth=true&usage_client_auth=true'
+[certs]
+renew = true
+reload_command = ["nginx", "-s", "reload"]
+EOF
 
-echo "Requesting certificates from dstack.sock..."
-echo "Using URL: $CERT_URL"
-echo "Using container image ID: $DSTACK_CONTAINER_IMAGE_ID"
+echo "Requesting certificates from the dstack agent..."
 echo "Using gateway domain: $DSTACK_GATEWAY_DOMAIN"
 
-if ! docker run --rm --name dstack-get-tls-key -v /var/run/dstack.sock:/var/run/dstack.sock $DSTACK_CONTAINER_IMAGE_ID \
-    curl -s --unix-socket /var/run/dstack.sock $CERT_URL >/tmp/server_response.json;
-then
+if ! dstack-mesh --config /etc/dstack/dstack-mesh.toml certs init; then
     echo "Failed to generate certificates - dstack.sock may not be available"
-    # Debug output
-    echo "Debug info - attempting to query dstack.sock directly:"
-    curl -s --unix-socket /var/run/dstack.sock http://localhost/Info
-    echo "Contents of /tmp/server_response.json:"
-    cat /tmp/server_response.json
     exit 1
 fi
 
-echo "Extracting server key and certificates..."
-jq -r '.key' /tmp/server_response.json >/etc/ssl/private/server.key
-jq -r '.certificate_chain[]' /tmp/server_response.json >/etc/ssl/certs/server.crt
-jq -r '.certificate_chain[-1]' /tmp/server_response.json >/etc/ssl/certs/ca.crt
-
-echo "Setting file permissions..."
-chmod 644 /etc/ssl/private/server.key /etc/ssl/certs/server.crt /etc/ssl/certs/ca.crt
-
 echo "Certificate generation completed!"
-rm -f /tmp/server_response.json
 
 echo "DSTACK_GATEWAY_DOMAIN=$DSTACK_GATEWAY_DOMAIN"
 echo "DSTACK_MESH_BACKEND: ${DSTACK_MESH_BACKEND}"
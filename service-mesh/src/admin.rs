@@ -0,0 +1,233 @@
+use anyhow::{bail, Context, Result};
+use rocket::http::Status;
+use rocket::request::{FromRequest, Outcome};
+use rocket::serde::json::Json;
+use rocket::{get, post, routes, Request, State};
+use rustls::pki_types::pem::PemObject;
+use rustls::pki_types::CertificateDer;
+use serde::Serialize;
+use serde_json::Value;
+use std::collections::BTreeMap;
+use std::net::IpAddr;
+use std::sync::Arc;
+use tokio_util::sync::CancellationToken;
+use tracing::{error, info, warn};
+
+use crate::certs::unix_now;
+use crate::config::{rocket_figment, AdminConfig, SharedConfig};
+use crate::identity::PeerIdentity;
+use crate::reload::Reloader;
+use crate::stats::{self, Denial, TargetStats};
+use crate::supervisor::{launch_rocket, ServiceControl, ServiceStatus, ServiceStatuses};
+
+/// Config keys whose values are replaced by [`REDACTED`]
+const SECRET_KEYS: &[&str] = &["secret", "token", "password", "credential"];
+const REDACTED: &str = "<redacted>";
+
+pub struct AdminState {
+    pub config: SharedConfig,
+    pub reloader: Arc<Reloader>,
+    pub statuses: ServiceStatuses,
+    pub control: ServiceControl,
+}
+
+/// Request guard for endpoints that change process state. Browsers send `Origin` with
+/// every POST, so this keeps any web page from reaching the admin API on loopback
+/// through the user's browser; command line clients do not send it.
+struct NotFromBrowser;
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for NotFromBrowser {
+    type Error = ();
+
+    async fn from_request(request: &'r Request<'_>) -> Outcome<Self, Self::Error> {
+        match request.headers().get_one("origin") {
+            Some(origin) => {
+                warn!(
+                    "Refused admin request {} from origin {origin}",
+                    request.uri()
+                );
+                Outcome::Error((Status::Forbidden, ()))
+            }
+            None => Outcome::Success(NotFromBrowser),
+        }
+    }
+}
+
+#[derive(Serialize)]
+struct OwnIdentity {
+    cert_file: String,
+    #[serde(flatten)]
+    identity: PeerIdentity,
+    /// Seconds until the certificate expires, negative once it has
+    expires_in: i64,
+}
+
+#[derive(Serialize)]
+struct BuildInfo {
+    version: String,
+}
+
+/// Effective configuration, with secrets and URL credentials redacted
+#[get("/config")]
+fn config_handler(state: &State<AdminState>) -> Result<Json<Value>, Status> {
+    let mut config = serde_json::to_value(&state.config.current().config).map_err(|e| {
+        error!("Failed to serialize configuration: {e}");
+        Status::InternalServerError
+    })?;
+    redact(&mut config);
+    Ok(Json(config))
+}
+
+/// Our own identity as carried by `tls.cert_file`
+#[get("/identity")]
+fn identity_handler(state: &State<AdminState>) -> Result<Json<OwnIdentity>, Status> {
+    let cert_file = state.config.current().config.tls.cert_file.clone();
+    let identity = CertificateDer::from_pem_file(&cert_file)
+        .context("Failed to read cert file")
+        .and_then(|cert| PeerIdentity::from_der(&cert))
+        .map_err(|err| {
+            error!("Failed to load identity from {cert_file}: {err:#}");
+            Status::InternalServerError
+        })?;
+    Ok(Json(OwnIdentity {
+        cert_file,
+        expires_in: identity.not_after - unix_now(),
+        identity,
+    }))
+}
+
+/// Per-target stats keyed by `<app_id>:<port>`, including the number of active
+/// requests and tunnels
+#[get("/upstreams")]
+fn upstreams_handler() -> Json<BTreeMap<String, TargetStats>> {
+    Json(stats::targets())
+}
+
+/// Recent auth denials, newest first
+#[get("/denials")]
+fn denials_handler() -> Json<Vec<Denial>> {
+    Json(stats::denials())
+}
+
+#[get("/services")]
+fn services_handler(state: &State<AdminState>) -> Json<BTreeMap<String, ServiceStatus>> {
+    let statuses = state.statuses.lock().unwrap_or_else(|e| e.into_inner());
+    Json(statuses.clone())
+}
+
+#[get("/version")]
+fn version_handler() -> Json<BuildInfo> {
+    Json(BuildInfo {
+        version: crate::app_version(),
+    })
+}
+
+/// Reload the configuration and certificates from disk
+#[post("/reload")]
+fn reload_handler(_guard: NotFromBrowser, state: &State<AdminState>) -> Status {
+    info!("Reload requested via admin API");
+    state.reloader.request();
+    Status::Accepted
+}
+
+/// Stop accepting connections, drain in-flight requests and exit
+#[post("/drain")]
+fn drain_handler(_guard: NotFromBrowser, state: &State<AdminState>) -> Status {
+    info!("Drain requested via admin API");
+    state.control.shutdown();
+    Status::Accepted
+}
+
+fn redact(value: &mut Value) {
+    match value {
+        Value::Object(map) => {
+            for (key, value) in map.iter_mut() {
+                let key = key.to_lowercase();
+                if SECRET_KEYS.iter().any(|secret| key.contains(secret)) {
+                    *value = Value::String(REDACTED.to_string());
+                } else {
+                    redact(value);
+                }
+            }
+        }
+        Value::Array(values) => values.iter_mut().for_each(redact),
+        Value::String(s) => {
+            if let Ok(mut url) = url::Url::parse(s) {
+                if url.password().is_some() {
+                    let _ = url.set_password(Some(REDACTED));
+                    *s = url.to_string();
+                }
+            }
+        }
+        _ => {}
+    }
+}
+
+/// The admin API must not be reachable from the network
+fn validate_address(config: &AdminConfig) -> Result<()> {
+    if config.address.starts_with("unix:") {
+        return Ok(());
+    }
+    let address: IpAddr = config
+        .address
+        .parse()
+        .with_context(|| format!("Invalid admin.address {}", config.address))?;
+    if !address.is_loopback() {
+        bail!("admin.address must be a loopback address or a unix socket");
+    }
+    Ok(())
+}
+
+/// Run the admin API with configuration from main figment
+pub async fn run_admin_api(
+    main_figment: &rocket::figment::Figment,
+    config: &AdminConfig,
+    state: AdminState,
+    shutdown: CancellationToken,
+) -> Result<()> {
+    validate_address(config)?;
+    let figment = rocket_figment(main_figment, "admin")?;
+    let rocket = rocket::custom(figment).manage(state).mount(
+        "/",
+        routes![
+            config_handler,
+            identity_handler,
+            upstreams_handler,
+            denials_handler,
+            services_handler,
+            version_handler,
+            reload_handler,
+            drain_handler,
+        ],
+    );
+    launch_rocket(rocket, shutdown).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rocket::http::Header;
+    use rocket::local::asynchronous::Client;
+
+    #[post("/drain")]
+    fn guarded(_guard: NotFromBrowser) -> Status {
+        Status::Accepted
+    }
+
+    #[tokio::test]
+    async fn refuses_state_changes_from_browsers() {
+        let rocket = rocket::build().mount("/", routes![guarded]);
+        let client = Client::untracked(rocket).await.unwrap();
+
+        let response = client.post("/drain").dispatch().await;
+        assert_eq!(response.status(), Status::Accepted);
+
+        let response = client
+            .post("/drain")
+            .header(Header::new("Origin", "https://example.com"))
+            .dispatch()
+            .await;
+        assert_eq!(response.status(), Status::Forbidden);
+    }
+}
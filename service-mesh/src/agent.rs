@@ -0,0 +1,81 @@
+use anyhow::{bail, Context, Result};
+use dstack_types::dstack_agent_address;
+use reqwest::Client;
+use serde::Deserialize;
+use std::time::Duration;
+
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Client for the dstack guest agent API at `dstack_agent_address()`
+pub struct AgentClient {
+    client: Client,
+    base_url: String,
+}
+
+/// Key and certificate chain issued by `GetTlsKey`, PEM-encoded
+#[derive(Debug, Deserialize)]
+pub struct TlsKeyResponse {
+    pub key: String,
+    /// Leaf first, root CA last
+    pub certificate_chain: Vec<String>,
+}
+
+impl AgentClient {
+    pub fn new() -> Result<Self> {
+        let agent_address = dstack_agent_address();
+        let mut client_builder = Client::builder().timeout(REQUEST_TIMEOUT);
+        let base_url = match agent_address.strip_prefix("unix:") {
+            Some(sock) => {
+                client_builder = client_builder.unix_socket(sock);
+                "http://localhost".to_string()
+            }
+            None => agent_address.trim_end_matches('/').to_string(),
+        };
+        let client = client_builder
+            .build()
+            .context("Failed to build agent client")?;
+        Ok(Self { client, base_url })
+    }
+
+    /// Check that the agent answers API calls
+    pub async fn ping(&self) -> Result<()> {
+        let response = self
+            .client
+            .get(format!("{}/Info", self.base_url))
+            .send()
+            .await
+            .context("Failed to call Info")?;
+        if !response.status().is_success() {
+            bail!("Info failed with {}", response.status());
+        }
+        Ok(())
+    }
+
+    /// Request a fresh RA-TLS key and certificate usable for both mesh client and server auth
+    pub async fn get_tls_key(&self, subject: &str) -> Result<TlsKeyResponse> {
+        let response = self
+            .client
+            .get(format!("{}/GetTlsKey", self.base_url))
+            .query(&[
+                ("subject", subject),
+                ("usage_server_auth", "true"),
+                ("usage_client_auth", "true"),
+            ])
+            .send()
+            .await
+            .context("Failed to call GetTlsKey")?;
+        if !response.status().is_success() {
+            let status = response.status();
+            let body = response.text().await.unwrap_or_default();
+            bail!("GetTlsKey failed with {status}: {body}");
+        }
+        let key: TlsKeyResponse = response
+            .json()
+            .await
+            .context("Failed to decode GetTlsKey response")?;
+        if key.certificate_chain.is_empty() {
+            bail!("GetTlsKey returned an empty certificate chain");
+        }
+        Ok(key)
+    }
+}
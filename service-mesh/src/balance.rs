@@ -0,0 +1,273 @@
+use std::collections::hash_map::DefaultHasher;
+use std::collections::HashMap;
+use std::hash::{Hash, Hasher};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant, SystemTime};
+use tracing::{info, warn};
+
+use crate::client::{is_hex_id, DstackRequest};
+use crate::config::{BalanceConfig, BalancePolicy};
+
+/// How often `instances_file` is checked for changes
+const DISCOVERY_CHECK_INTERVAL: Duration = Duration::from_secs(5);
+
+type Outstanding = Arc<Mutex<HashMap<String, usize>>>;
+
+/// Picks an instance of one app for each request
+pub struct Balancer {
+    config: BalanceConfig,
+    discovered: Mutex<Discovered>,
+    next: AtomicUsize,
+    outstanding: Outstanding,
+}
+
+/// Instances read from `instances_file`
+#[derive(Default)]
+struct Discovered {
+    checked: Option<Instant>,
+    modified: Option<SystemTime>,
+    instances: Vec<String>,
+}
+
+/// The instance chosen for a request, counted as outstanding until dropped
+pub struct Pick {
+    pub instance_id: String,
+    /// `Set-Cookie` value pinning the caller to the instance
+    pub set_cookie: Option<String>,
+    outstanding: Outstanding,
+}
+
+impl Drop for Pick {
+    fn drop(&mut self) {
+        let mut outstanding = self.outstanding.lock().unwrap_or_else(|e| e.into_inner());
+        if let Some(count) = outstanding.get_mut(&self.instance_id) {
+            *count = count.saturating_sub(1);
+        }
+    }
+}
+
+impl Balancer {
+    pub fn new(config: &BalanceConfig) -> Self {
+        let mut config = config.clone();
+        config
+            .instances
+            .retain(|id| valid_instance(id, "balance.instances"));
+        Self {
+            config,
+            discovered: Default::default(),
+            next: AtomicUsize::new(0),
+            outstanding: Default::default(),
+        }
+    }
+
+    pub fn sticky_cookie(&self) -> Option<&str> {
+        self.config.sticky_cookie.as_deref()
+    }
+
+    /// Configured and discovered instance ids, lowercased and deduplicated
+    fn instances(&self) -> Vec<String> {
+        let mut instances: Vec<String> = self
+            .config
+            .instances
+            .iter()
+            .map(|id| id.to_lowercase())
+            .collect();
+        if let Some(file) = &self.config.instances_file {
+            let mut discovered = self.discovered.lock().unwrap_or_else(|e| e.into_inner());
+            discovered.refresh(file);
+            instances.extend(discovered.instances.iter().cloned());
+        }
+        let mut seen = std::collections::HashSet::new();
+        instances.retain(|id| seen.insert(id.clone()));
+        instances
+    }
+
+    /// Choose an instance for a request (or a tunnel, without one). Instances for
+    /// which `available` returns false are skipped unless none is available.
+    /// Returns `None` if the app has no known instances.
+    pub fn pick(
+        &self,
+        request: Option<&DstackRequest>,
+        available: impl Fn(&str) -> bool,
+    ) -> Option<Pick> {
+        let all = self.instances();
+        let healthy: Vec<String> = all.iter().filter(|id| available(id)).cloned().collect();
+        let instances = if healthy.is_empty() { all } else { healthy };
+        if instances.is_empty() {
+            return None;
+        }
+
+        let header = |name: &Option<String>| {
+            name.as_deref()
+                .and_then(|name| request.and_then(|request| request.header(name)))
+        };
+        let cookie = self
+            .config
+            .sticky_cookie
+            .as_deref()
+            .and_then(|name| request.and_then(|request| request_cookie(request, name)))
+            .map(|value| value.to_lowercase());
+
+        let (instance_id, pinned) = if let Some(id) = cookie.filter(|id| instances.contains(id)) {
+            (id, true)
+        } else if let Some(key) = header(&self.config.sticky_header) {
+            (rendezvous(&instances, key), false)
+        } else {
+            let instance_id = match self.config.policy {
+                BalancePolicy::RoundRobin => self.round_robin(&instances),
+                BalancePolicy::LeastOutstanding => self.least_outstanding(&instances),
+                BalancePolicy::ConsistentHash => {
+                    let key = header(&self.config.hash_header)
+                        .or(request.map(|request| request.path.as_str()))
+                        .unwrap_or_default();
+                    rendezvous(&instances, key)
+                }
+            };
+            (instance_id, false)
+        };
+
+        *self
+            .outstanding
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .entry(instance_id.clone())
+            .or_default() += 1;
+        let set_cookie = match &self.config.sticky_cookie {
+            Some(name) if !pinned => Some(format!("{name}={instance_id}; Path=/; HttpOnly")),
+            _ => None,
+        };
+        Some(Pick {
+            instance_id,
+            set_cookie,
+            outstanding: self.outstanding.clone(),
+        })
+    }
+
+    fn round_robin(&self, instances: &[String]) -> String {
+        let next = self.next.fetch_add(1, Ordering::Relaxed);
+        instances[next % instances.len()].clone()
+    }
+
+    fn least_outstanding(&self, instances: &[String]) -> String {
+        let outstanding = self.outstanding.lock().unwrap_or_else(|e| e.into_inner());
+        // Rotate the starting point so that ties are spread round-robin
+        let start = self.next.fetch_add(1, Ordering::Relaxed);
+        (0..instances.len())
+            .map(|i| &instances[(start + i) % instances.len()])
+            .min_by_key(|id| outstanding.get(*id).copied().unwrap_or_default())
+            .cloned()
+            .unwrap_or_default()
+    }
+}
+
+impl Discovered {
+    fn refresh(&mut self, file: &str) {
+        if self
+            .checked
+            .is_some_and(|checked| checked.elapsed() < DISCOVERY_CHECK_INTERVAL)
+        {
+            return;
+        }
+        self.checked = Some(Instant::now());
+        let modified = match fs_err::metadata(file).and_then(|meta| meta.modified()) {
+            Ok(modified) => modified,
+            Err(err) => {
+                warn!("Failed to check instances file: {err}");
+                return;
+            }
+        };
+        if self.modified == Some(modified) {
+            return;
+        }
+        match fs_err::read_to_string(file) {
+            Ok(contents) => {
+                self.instances = contents
+                    .lines()
+                    .map(str::trim)
+                    .filter(|line| !line.is_empty() && !line.starts_with('#'))
+                    .filter(|id| valid_instance(id, file))
+                    .map(|id| id.to_lowercase())
+                    .collect();
+                self.modified = Some(modified);
+                info!("Loaded {} instance(s) from {file}", self.instances.len());
+            }
+            // Keep the previous instances
+            Err(err) => warn!("Failed to read instances file: {err}"),
+        }
+    }
+}
+
+/// Instance ids end up in upstream host names, cookies and metrics, so only hex ids
+/// are used, as for the `x-dstack-target-instance` header
+fn valid_instance(id: &str, source: &str) -> bool {
+    let valid = !id.is_empty() && is_hex_id(id);
+    if !valid {
+        warn!("Skipping invalid instance id '{id}' from {source}");
+    }
+    valid
+}
+
+/// Highest-random-weight hashing: a key keeps its instance as long as that
+/// instance is in the set, and only the keys of a removed instance move
+fn rendezvous(instances: &[String], key: &str) -> String {
+    instances
+        .iter()
+        .max_by_key(|id| {
+            let mut hasher = DefaultHasher::new();
+            (id.as_str(), key).hash(&mut hasher);
+            hasher.finish()
+        })
+        .cloned()
+        .unwrap_or_default()
+}
+
+fn request_cookie<'a>(request: &'a DstackRequest, name: &str) -> Option<&'a str> {
+    request
+        .all_headers
+        .iter()
+        .filter(|(header, _)| header.eq_ignore_ascii_case("cookie"))
+        .flat_map(|(_, value)| value.split(';'))
+        .filter_map(|pair| pair.trim().split_once('='))
+        .find(|(cookie, _)| *cookie == name)
+        .map(|(_, value)| value)
+}
+
+/// A `Cookie` header value without the cookie `name`, or `None` if nothing is left
+pub(crate) fn strip_cookie(value: &str, name: &str) -> Option<String> {
+    let rest: Vec<&str> = value
+        .split(';')
+        .map(str::trim)
+        .filter(|pair| !pair.is_empty())
+        .filter(|pair| pair.split_once('=').map_or(*pair, |(cookie, _)| cookie) != name)
+        .collect();
+    (!rest.is_empty()).then(|| rest.join("; "))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn strips_the_sticky_cookie() {
+        assert_eq!(
+            strip_cookie("a=1; mesh-instance=ab12; b=2", "mesh-instance"),
+            Some("a=1; b=2".to_string())
+        );
+        assert_eq!(strip_cookie("mesh-instance=ab12", "mesh-instance"), None);
+        assert_eq!(
+            strip_cookie("a=1", "mesh-instance"),
+            Some("a=1".to_string())
+        );
+    }
+
+    #[test]
+    fn skips_invalid_discovered_instances() {
+        let dir = tempfile::tempdir().unwrap();
+        let file = dir.path().join("instances");
+        std::fs::write(&file, "AB12\nnot-hex\n# comment\nab12.evil.com\ncd34\n").unwrap();
+        let mut discovered = Discovered::default();
+        discovered.refresh(file.to_str().unwrap());
+        assert_eq!(discovered.instances, ["ab12", "cd34"]);
+    }
+}
@@ -0,0 +1,373 @@
+use std::collections::HashMap;
+use std::fmt;
+use std::sync::{LazyLock, Mutex};
+use std::time::{Duration, Instant};
+use tracing::{info, warn};
+
+use crate::config::{CircuitBreakerConfig, TargetInfo};
+use crate::metrics;
+
+/// Breakers keyed by `(app_id, instance_id, port)`, kept across reloads
+static BREAKERS: LazyLock<Mutex<HashMap<BreakerKey, Breaker>>> = LazyLock::new(Default::default);
+
+/// Past this many breakers, closed ones are dropped to make room for new targets
+const MAX_BREAKERS: usize = 4096;
+/// Closed breakers unused for this long are dropped first
+const IDLE_EVICT: Duration = Duration::from_secs(600);
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+struct BreakerKey {
+    app_id: String,
+    instance_id: String,
+    port: u16,
+}
+
+impl BreakerKey {
+    fn new(target: &TargetInfo) -> Self {
+        Self {
+            app_id: target.app_id.to_lowercase(),
+            instance_id: target.instance_id.to_lowercase(),
+            port: target.port,
+        }
+    }
+}
+
+impl fmt::Display for BreakerKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.instance_id.is_empty() {
+            write!(f, "{}:{}", self.app_id, self.port)
+        } else {
+            write!(f, "{}/{}:{}", self.app_id, self.instance_id, self.port)
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum State {
+    Closed,
+    Open { until: Instant },
+    HalfOpen { probes: u32 },
+}
+
+impl State {
+    fn name(&self) -> &'static str {
+        match self {
+            State::Closed => "closed",
+            State::Open { .. } => "open",
+            State::HalfOpen { .. } => "half_open",
+        }
+    }
+
+    /// Value of `dstack_mesh_circuit_state`
+    fn gauge(&self) -> i64 {
+        match self {
+            State::Closed => 0,
+            State::Open { .. } => 1,
+            State::HalfOpen { .. } => 2,
+        }
+    }
+}
+
+struct Breaker {
+    state: State,
+    consecutive_failures: u32,
+    window_start: Instant,
+    window_requests: u32,
+    window_failures: u32,
+    /// How long the circuit stays open the next time it opens
+    open_for: Duration,
+    last_used: Instant,
+}
+
+impl Breaker {
+    fn new(config: &CircuitBreakerConfig) -> Self {
+        Self {
+            state: State::Closed,
+            consecutive_failures: 0,
+            window_start: Instant::now(),
+            window_requests: 0,
+            window_failures: 0,
+            open_for: Duration::from_secs(config.open_secs),
+            last_used: Instant::now(),
+        }
+    }
+
+    fn transition(&mut self, key: &BreakerKey, state: State) {
+        if self.state.name() != state.name() {
+            metrics::record_circuit_state(
+                &key.app_id,
+                &key.instance_id,
+                key.port,
+                state.name(),
+                state.gauge(),
+            );
+        }
+        self.state = state;
+    }
+
+    fn open(&mut self, key: &BreakerKey, config: &CircuitBreakerConfig, reason: &str) {
+        warn!("Circuit to {key} opened for {:?}: {reason}", self.open_for);
+        let until = Instant::now() + self.open_for;
+        self.transition(key, State::Open { until });
+        // Peers that keep failing their probes are left alone for longer
+        self.open_for = (self.open_for * 2).min(Duration::from_secs(config.max_open_secs));
+        self.consecutive_failures = 0;
+        self.reset_window();
+    }
+
+    fn close(&mut self, key: &BreakerKey, config: &CircuitBreakerConfig) {
+        info!("Circuit to {key} closed");
+        self.transition(key, State::Closed);
+        self.open_for = Duration::from_secs(config.open_secs);
+        self.consecutive_failures = 0;
+        self.reset_window();
+    }
+
+    fn reset_window(&mut self) {
+        self.window_start = Instant::now();
+        self.window_requests = 0;
+        self.window_failures = 0;
+    }
+
+    fn record(
+        &mut self,
+        key: &BreakerKey,
+        config: &CircuitBreakerConfig,
+        success: bool,
+        probe: bool,
+    ) {
+        match self.state {
+            State::HalfOpen { .. } if probe => {
+                if success {
+                    self.close(key, config);
+                } else {
+                    self.open(key, config, "probe failed");
+                }
+                return;
+            }
+            State::Closed => {}
+            // Outcome of a request that started before the circuit opened; only
+            // probes decide whether it closes again
+            _ => return,
+        }
+
+        if self.window_start.elapsed() >= Duration::from_secs(config.window_secs) {
+            self.reset_window();
+        }
+        self.window_requests += 1;
+        if success {
+            self.consecutive_failures = 0;
+            return;
+        }
+        self.window_failures += 1;
+        self.consecutive_failures += 1;
+
+        if self.consecutive_failures >= config.consecutive_failures {
+            let reason = format!("{} consecutive failures", self.consecutive_failures);
+            self.open(key, config, &reason);
+        } else if self.window_requests >= config.min_requests
+            && self.window_failures as f64 >= config.error_rate * self.window_requests as f64
+        {
+            let reason = format!(
+                "{} of {} requests failed",
+                self.window_failures, self.window_requests
+            );
+            self.open(key, config, &reason);
+        }
+    }
+}
+
+/// Returned instead of a [`Permit`] while the circuit to a target is open
+#[derive(Debug)]
+pub struct CircuitOpen {
+    target: String,
+    retry_in: Duration,
+}
+
+impl fmt::Display for CircuitOpen {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "Circuit to {} is open after repeated failures, retry in {}s",
+            self.target,
+            self.retry_in.as_secs().max(1)
+        )
+    }
+}
+
+impl std::error::Error for CircuitOpen {}
+
+/// Lets one call through to a target; report its outcome with [`Permit::success`]
+/// or [`Permit::failure`]
+pub struct Permit {
+    key: Option<BreakerKey>,
+    config: CircuitBreakerConfig,
+    probe: bool,
+}
+
+impl Permit {
+    pub fn success(self) {
+        self.finish(true);
+    }
+
+    pub fn failure(self) {
+        self.finish(false);
+    }
+
+    fn finish(mut self, success: bool) {
+        if let Some(key) = self.key.take() {
+            let mut breakers = BREAKERS.lock().unwrap_or_else(|e| e.into_inner());
+            if let Some(breaker) = breakers.get_mut(&key) {
+                breaker.record(&key, &self.config, success, self.probe);
+            }
+        }
+    }
+}
+
+impl Drop for Permit {
+    fn drop(&mut self) {
+        // Abandoned before an outcome, e.g. the caller went away: free the probe slot
+        let Some(key) = self.key.take() else {
+            return;
+        };
+        if !self.probe {
+            return;
+        }
+        let mut breakers = BREAKERS.lock().unwrap_or_else(|e| e.into_inner());
+        if let Some(breaker) = breakers.get_mut(&key) {
+            if let State::HalfOpen { probes } = &mut breaker.state {
+                *probes = probes.saturating_sub(1);
+            }
+        }
+    }
+}
+
+/// Whether calls to the target are currently refused
+pub fn is_open(config: &CircuitBreakerConfig, target: &TargetInfo) -> bool {
+    if !config.enabled {
+        return false;
+    }
+    let breakers = BREAKERS.lock().unwrap_or_else(|e| e.into_inner());
+    matches!(
+        breakers.get(&BreakerKey::new(target)).map(|breaker| breaker.state),
+        Some(State::Open { until }) if until > Instant::now()
+    )
+}
+
+/// Ask to call a target. Fails fast while its circuit is open; once the open period
+/// has passed, lets `half_open_probes` calls through to probe for recovery.
+pub fn acquire(config: &CircuitBreakerConfig, target: &TargetInfo) -> Result<Permit, CircuitOpen> {
+    if !config.enabled {
+        return Ok(Permit {
+            key: None,
+            config: config.clone(),
+            probe: false,
+        });
+    }
+    let key = BreakerKey::new(target);
+    let mut breakers = BREAKERS.lock().unwrap_or_else(|e| e.into_inner());
+    if breakers.len() >= MAX_BREAKERS && !breakers.contains_key(&key) {
+        evict(&mut breakers);
+    }
+    let breaker = breakers
+        .entry(key.clone())
+        .or_insert_with(|| Breaker::new(config));
+    breaker.last_used = Instant::now();
+
+    let mut probe = false;
+    match breaker.state {
+        State::Closed => {}
+        State::Open { until } => {
+            let now = Instant::now();
+            if now < until {
+                return Err(CircuitOpen {
+                    target: key.to_string(),
+                    retry_in: until - now,
+                });
+            }
+            info!("Circuit to {key} half-open, probing");
+            breaker.transition(&key, State::HalfOpen { probes: 1 });
+            probe = true;
+        }
+        State::HalfOpen { probes } => {
+            if probes >= config.half_open_probes.max(1) {
+                return Err(CircuitOpen {
+                    target: key.to_string(),
+                    retry_in: Duration::ZERO,
+                });
+            }
+            breaker.transition(&key, State::HalfOpen { probes: probes + 1 });
+            probe = true;
+        }
+    }
+    Ok(Permit {
+        key: Some(key),
+        config: config.clone(),
+        probe,
+    })
+}
+
+/// Drop closed breakers that have been idle, or failing that the least recently used
+/// closed one. Open and half-open breakers are kept so failing targets stay blocked.
+fn evict(breakers: &mut HashMap<BreakerKey, Breaker>) {
+    breakers.retain(|_, breaker| {
+        breaker.state != State::Closed || breaker.last_used.elapsed() < IDLE_EVICT
+    });
+    if breakers.len() < MAX_BREAKERS {
+        return;
+    }
+    let oldest = breakers
+        .iter()
+        .filter(|(_, breaker)| breaker.state == State::Closed)
+        .min_by_key(|(_, breaker)| breaker.last_used)
+        .map(|(key, _)| key.clone());
+    if let Some(key) = oldest {
+        breakers.remove(&key);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn config() -> CircuitBreakerConfig {
+        CircuitBreakerConfig {
+            enabled: true,
+            consecutive_failures: 1,
+            error_rate: 1.0,
+            min_requests: 100,
+            window_secs: 60,
+            open_secs: 0,
+            max_open_secs: 0,
+            half_open_probes: 1,
+            failure_statuses: vec![],
+        }
+    }
+
+    fn target(app_id: &str) -> TargetInfo {
+        TargetInfo {
+            app_id: app_id.to_string(),
+            instance_id: String::new(),
+            port: 80,
+        }
+    }
+
+    #[test]
+    fn only_probes_close_a_half_open_circuit() {
+        let config = config();
+        let target = target("breaker-probe-test");
+        let stale = acquire(&config, &target).unwrap();
+        acquire(&config, &target).unwrap().failure();
+
+        // The open period is zero, so the next call probes
+        let probe = acquire(&config, &target).unwrap();
+        stale.success();
+        assert!(
+            acquire(&config, &target).is_err(),
+            "stale success closed the circuit"
+        );
+
+        probe.success();
+        assert!(acquire(&config, &target).is_ok());
+    }
+}
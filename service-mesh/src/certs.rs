@@ -0,0 +1,200 @@
+use anyhow::{bail, Context, Result};
+use std::fs::Permissions;
+use std::io::Write;
+use std::os::unix::fs::PermissionsExt;
+use std::path::Path;
+use std::sync::Arc;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tempfile::NamedTempFile;
+use tokio::process::Command;
+use tokio_util::sync::CancellationToken;
+use tracing::{debug, error, info, warn};
+
+use crate::agent::AgentClient;
+use crate::config::{SharedConfig, TlsConfig};
+use crate::reload::Reloader;
+use crate::tls::TlsMaterial;
+
+/// Subject requested from the agent; peers identify us by app_id, not by name
+const SUBJECT: &str = "localhost";
+/// Wait between failed renewal attempts
+const RETRY_INTERVAL: Duration = Duration::from_secs(60);
+/// Upper bound between checks, so externally replaced certificates are noticed
+const CHECK_INTERVAL: Duration = Duration::from_secs(3600);
+
+/// Fetch a new key and certificate from the dstack agent and install them in the `tls.*` files
+pub async fn issue(tls: &TlsConfig) -> Result<()> {
+    let issued = AgentClient::new()?.get_tls_key(SUBJECT).await?;
+
+    let pem = |cert: &String| format!("{}\n", cert.trim_end());
+    let chain: String = issued.certificate_chain.iter().map(pem).collect();
+    let ca = issued
+        .certificate_chain
+        .last()
+        .map(pem)
+        .context("Empty certificate chain")?;
+
+    validate_issued(&issued.key, &chain, &ca)?;
+
+    // Write all files before replacing any, and replace the certificate last, so that
+    // a new certificate is never seen next to the previous key
+    let staged = [
+        stage(&tls.ca_file, ca.as_bytes(), 0o644)?,
+        stage(&tls.key_file, issued.key.as_bytes(), 0o600)?,
+        stage(&tls.cert_file, chain.as_bytes(), 0o644)?,
+    ];
+    for (file, path) in staged {
+        file.persist(path)
+            .with_context(|| format!("Failed to replace {}", path.display()))?;
+    }
+    Ok(())
+}
+
+/// Check an issued key and chain in memory, so that a broken pair from the agent never
+/// replaces the working one on disk
+fn validate_issued(key: &str, chain: &str, ca: &str) -> Result<()> {
+    TlsMaterial::from_pem(key.as_bytes(), chain.as_bytes(), ca.as_bytes())?
+        .verify_own_chain()
+        .context("Issued certificate chain is incomplete")
+}
+
+/// Write `contents` to a temporary file next to `path`, to be renamed over it so that
+/// readers never see a partially written file
+fn stage(path: &str, contents: &[u8], mode: u32) -> Result<(NamedTempFile, &Path)> {
+    let path = Path::new(path);
+    let dir = match path.parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => dir,
+        _ => Path::new("."),
+    };
+    fs_err::create_dir_all(dir)?;
+    let mut file = tempfile::NamedTempFile::new_in(dir)
+        .with_context(|| format!("Failed to create temporary file in {}", dir.display()))?;
+    // Restrict permissions before any secret is written
+    file.as_file()
+        .set_permissions(Permissions::from_mode(mode))
+        .context("Failed to set file permissions")?;
+    file.write_all(contents)
+        .with_context(|| format!("Failed to write {}", path.display()))?;
+    file.as_file()
+        .sync_all()
+        .with_context(|| format!("Failed to sync {}", path.display()))?;
+    Ok((file, path))
+}
+
+/// Run `certs.reload_command`, e.g. to make nginx load the renewed certificate
+async fn run_reload_command(command: &[String]) -> Result<()> {
+    let Some((program, args)) = command.split_first() else {
+        return Ok(());
+    };
+    let status = Command::new(program)
+        .args(args)
+        .status()
+        .await
+        .with_context(|| format!("Failed to run {program}"))?;
+    if !status.success() {
+        bail!("{program} exited with {status}");
+    }
+    Ok(())
+}
+
+/// Validity period of the leaf certificate in `cert_file`, as unix timestamps
+pub fn cert_validity(cert_file: &str) -> Result<(i64, i64)> {
+    let pem = fs_err::read(cert_file)?;
+    let (_, pem) = x509_parser::pem::parse_x509_pem(&pem).context("Failed to parse cert file")?;
+    let cert = pem.parse_x509().context("Failed to parse certificate")?;
+    let validity = cert.validity();
+    Ok((
+        validity.not_before.timestamp(),
+        validity.not_after.timestamp(),
+    ))
+}
+
+pub fn unix_now() -> i64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs() as i64)
+        .unwrap_or_default()
+}
+
+/// Time until the certificate should be renewed: `renew_before` ahead of expiry,
+/// but never earlier than two thirds into its lifetime
+fn renew_in(cert_file: &str, renew_before: u64) -> Result<Duration> {
+    let (not_before, not_after) = cert_validity(cert_file)?;
+    let margin = (renew_before as i64).min((not_after - not_before) / 3);
+    let remaining = not_after - margin - unix_now();
+    Ok(Duration::from_secs(remaining.max(0) as u64))
+}
+
+/// Re-issue the certificate before it expires and reload the running services with it
+pub async fn run_renewer(
+    config: SharedConfig,
+    reloader: Arc<Reloader>,
+    shutdown: CancellationToken,
+) -> Result<()> {
+    loop {
+        let loaded = config.current();
+        let tls = &loaded.config.tls;
+        let wait = match renew_in(&tls.cert_file, loaded.config.certs.renew_before_secs) {
+            Ok(wait) => wait,
+            Err(err) => {
+                warn!("Failed to read the current certificate, renewing now: {err:?}");
+                Duration::ZERO
+            }
+        };
+
+        let delay = if wait.is_zero() {
+            info!("Renewing certificate through the dstack agent");
+            match issue(tls).await {
+                Ok(()) => {
+                    info!("Certificate renewed");
+                    reloader.request();
+                    if let Err(err) = run_reload_command(&loaded.config.certs.reload_command).await
+                    {
+                        error!("Reload command failed after renewal: {err:?}");
+                    }
+                    continue;
+                }
+                Err(err) => {
+                    error!("Certificate renewal failed, retrying in {RETRY_INTERVAL:?}: {err:?}");
+                    RETRY_INTERVAL
+                }
+            }
+        } else {
+            debug!("Next certificate renewal in {wait:?}");
+            wait.min(CHECK_INTERVAL)
+        };
+
+        tokio::select! {
+            _ = shutdown.cancelled() => return Ok(()),
+            _ = tokio::time::sleep(delay) => {}
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::TestMesh;
+
+    #[test]
+    fn validates_issued_certificates_before_installing() {
+        let mesh = TestMesh::new();
+        let tls = mesh.config("").tls;
+        let read = |path: &str| std::fs::read_to_string(path).unwrap();
+        let (key, chain, ca) = (
+            read(&tls.key_file),
+            read(&tls.cert_file),
+            read(&tls.ca_file),
+        );
+        assert!(validate_issued(&key, &chain, &ca).is_ok());
+
+        let other_key = rcgen::KeyPair::generate().unwrap().serialize_pem();
+        let err = validate_issued(&other_key, &chain, &ca).unwrap_err();
+        assert!(format!("{err:#}").contains("does not match the certificate"));
+
+        // A chain that does not lead to the CA it came with
+        let other = TestMesh::new().config("").tls;
+        let err = validate_issued(&key, &chain, &read(&other.ca_file)).unwrap_err();
+        assert!(format!("{err:#}").contains("incomplete"));
+    }
+}
@@ -1,30 +1,315 @@
 use anyhow::{bail, Context, Result};
+use bytes::{Bytes, BytesMut};
 use dstack_types::dstack_agent_address;
 use heck::ToPascalCase;
-use ra_tls::traits::CertExt as _;
 use reqwest::redirect::Policy;
 use reqwest::tls::TlsInfo;
 use reqwest::Client;
-use rocket::figment::providers::Serialized;
+use rocket::data::{ByteUnit, IoHandler, IoStream};
 use rocket::figment::Figment;
 use rocket::http::uri::fmt::Path;
 use rocket::http::uri::Segments;
-use rocket::http::Status;
+use rocket::http::{ContentType, Status};
 use rocket::request::{self, FromRequest};
 use rocket::response::{Responder, Response};
+use rocket::serde::json::Json;
 use rocket::tokio::io::AsyncRead;
-use rocket::{get, post, routes, Data, Request, State};
+use rocket::{get, post, routes, Data, Either, Request, State};
+use rustls::pki_types::ServerName;
+use std::collections::{BTreeMap, HashMap};
+use std::net::SocketAddr;
 use std::pin::Pin;
+use std::sync::{Arc, Mutex, RwLock};
 use std::task::{Context as TaskContext, Poll};
+use std::time::{Duration, Instant};
 use tokio::io::AsyncReadExt;
-use tracing::{debug, info, warn};
-
-use crate::config::Config;
+use tokio::net::TcpStream;
+use tokio::sync::mpsc;
+use tokio_rustls::client::TlsStream;
+use tokio_rustls::TlsConnector;
+use tokio_util::sync::CancellationToken;
+use tracing::{debug, field, info, info_span, warn, Instrument};
+use tracing_opentelemetry::OpenTelemetrySpanExt;
+
+use crate::balance::{strip_cookie, Balancer, Pick};
+use crate::breaker;
 use crate::config::TargetInfo;
+use crate::config::{
+    rocket_figment, CircuitBreakerConfig, ClientConfig, Config, ForwardProxyConfig, ServiceConfig,
+};
+use crate::metrics;
+use crate::mux;
+use crate::policy::PeerPolicy;
+use crate::ready::{self, Readiness};
+use crate::retry::{RetryPolicy, RetryReason};
+use crate::stats;
+use crate::supervisor::launch_rocket;
+use crate::telemetry;
+use crate::tls::{verify_peer, TlsMaterial};
+
+/// Size of each chunk read from an incoming request body
+const BODY_CHUNK_SIZE: usize = 64 * 1024;
+/// Number of chunks buffered between the incoming body and the upstream request
+const BODY_CHANNEL_CAPACITY: usize = 8;
+
+/// Longest app_id or instance_id accepted as a target
+const MAX_ID_LEN: usize = 64;
+/// Pooled mTLS clients kept, one per target app (and instance)
+const MAX_MTLS_CLIENTS: usize = 256;
+
+/// Headers that only apply to a single connection and must not be forwarded
+const HOP_BY_HOP_HEADERS: &[&str] = &[
+    "connection",
+    "keep-alive",
+    "proxy-authenticate",
+    "proxy-authorization",
+    "proxy-connection",
+    "te",
+    "trailer",
+    "transfer-encoding",
+    "upgrade",
+];
 
 pub struct ClientState {
+    /// Routing state and mTLS identity, swapped as a whole on reload. In-flight
+    /// requests keep the snapshot they started with.
+    current: RwLock<Arc<ClientRuntime>>,
+}
+
+impl ClientState {
+    pub fn new(config: &Config) -> Result<Self> {
+        Ok(Self {
+            current: RwLock::new(Arc::new(ClientRuntime::new(config)?)),
+        })
+    }
+
+    /// The routing state and mTLS identity currently in effect
+    pub fn runtime(&self) -> Arc<ClientRuntime> {
+        self.current
+            .read()
+            .unwrap_or_else(|e| e.into_inner())
+            .clone()
+    }
+
+    /// Atomically switch new requests over to `runtime`
+    pub fn replace(&self, runtime: ClientRuntime) {
+        *self.current.write().unwrap_or_else(|e| e.into_inner()) = Arc::new(runtime);
+    }
+
+    /// See [`ClientRuntime::balance`]
+    pub fn balance(&self, target: &mut TargetInfo) -> Option<Pick> {
+        self.runtime().balance(target, None)
+    }
+
+    /// Open a raw mTLS stream to the target using the current identity
+    pub async fn connect_upstream(&self, target: &TargetInfo) -> Result<TlsStream<TcpStream>> {
+        let runtime = self.runtime();
+        let permit = match breaker::acquire(&runtime.client_config.circuit_breaker, target) {
+            Ok(permit) => permit,
+            Err(open) => {
+                metrics::record_upstream_error(&target.app_id, "circuit_open");
+                return Err(open.into());
+            }
+        };
+        let result = runtime.connect_upstream(target, &[]).await;
+        match &result {
+            Ok(_) => permit.success(),
+            Err(_) => permit.failure(),
+        }
+        if let Err(err) = &result {
+            metrics::record_upstream_error(
+                &target.app_id,
+                metrics::upstream_error_kind(err.as_ref()),
+            );
+            stats::record_error(&target.app_id, target.port, format!("{err:#}"));
+        }
+        result
+    }
+}
+
+/// Everything the outbound proxies derive from the configuration and TLS files
+pub struct ClientRuntime {
     gateway_domain: String,
-    http_client: Client,
+    tls: TlsMaterial,
+    /// mTLS clients keyed by the app_id their verifier expects, so that pooled
+    /// connections are never reused for a different peer identity, with the time
+    /// each was last used
+    mtls_clients: Mutex<HashMap<String, (Client, Instant)>>,
+    client_config: ClientConfig,
+    retry: RetryPolicy,
+    /// Load balancers keyed by lowercase app_id
+    balancers: HashMap<String, Balancer>,
+    services: BTreeMap<String, ServiceConfig>,
+}
+
+impl ClientRuntime {
+    pub fn new(config: &Config) -> Result<Self> {
+        // Load the mTLS identity used for all upstream connections
+        let tls = TlsMaterial::load(&config.tls).context("Failed to load TLS material")?;
+        Ok(Self {
+            gateway_domain: config.dstack.gateway_domain.clone(),
+            tls,
+            mtls_clients: Mutex::new(HashMap::new()),
+            client_config: config.client.clone(),
+            retry: RetryPolicy::new(&config.client.retry),
+            balancers: config
+                .client
+                .balance
+                .iter()
+                .map(|balance| (balance.app_id.to_lowercase(), Balancer::new(balance)))
+                .collect(),
+            services: config.services.clone(),
+        })
+    }
+
+    pub(crate) fn circuit_breaker(&self) -> &CircuitBreakerConfig {
+        &self.client_config.circuit_breaker
+    }
+
+    pub(crate) fn forward_proxy(&self) -> &ForwardProxyConfig {
+        &self.client_config.forward_proxy
+    }
+
+    /// Host (and optional port) to connect to for reaching the given target
+    pub(crate) fn upstream_authority(&self, target: &TargetInfo, use_tls: bool) -> String {
+        gateway_authority(&self.gateway_domain, target, use_tls)
+    }
+
+    /// Open a raw mTLS stream to the target through the gateway.
+    ///
+    /// The handshake fails unless the peer presents an RA-TLS certificate for `target.app_id`.
+    pub async fn connect_upstream(
+        &self,
+        target: &TargetInfo,
+        protocols: &[&[u8]],
+    ) -> Result<TlsStream<TcpStream>> {
+        const CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+
+        let authority = self.upstream_authority(target, true);
+        let (host, port) = match authority.rsplit_once(':') {
+            Some((host, port)) => (host, port.parse().context("Invalid gateway port")?),
+            None => (authority.as_str(), 443),
+        };
+        let server_name =
+            ServerName::try_from(host.to_string()).context("Invalid upstream host name")?;
+        let policy = self.target_policy(target)?;
+        let mut tls_config = self.tls.client_config(&target.app_id, policy.as_ref())?;
+        tls_config.alpn_protocols = protocols.iter().map(|p| p.to_vec()).collect();
+
+        let stream = tokio::time::timeout(CONNECT_TIMEOUT, async {
+            let tcp = TcpStream::connect((host, port))
+                .await
+                .with_context(|| format!("Failed to connect to {host}:{port}"))?;
+            TlsConnector::from(Arc::new(tls_config))
+                .connect(server_name, tcp)
+                .await
+                .context("TLS handshake failed")
+        })
+        .await
+        .context("Timed out connecting to upstream")??;
+        Ok(stream)
+    }
+
+    /// Get the pooled mTLS client that only talks to peers with the target's app_id,
+    /// and its instance_id when the target names one
+    fn mtls_client(&self, target: &TargetInfo) -> Result<Client> {
+        let key = if target.instance_id.is_empty() {
+            target.app_id.to_lowercase()
+        } else {
+            format!("{}/{}", target.app_id, target.instance_id).to_lowercase()
+        };
+        let mut clients = self.mtls_clients.lock().unwrap_or_else(|e| e.into_inner());
+        if let Some((client, last_used)) = clients.get_mut(&key) {
+            *last_used = Instant::now();
+            return Ok(client.clone());
+        }
+        let policy = self.target_policy(target)?;
+        let client = create_mtls_client(&self.tls, &target.app_id, policy.as_ref())?;
+        if clients.len() >= MAX_MTLS_CLIENTS {
+            // Drop the least recently used client and its idle connections
+            let oldest = clients
+                .iter()
+                .min_by_key(|(_, (_, last_used))| *last_used)
+                .map(|(key, _)| key.clone());
+            if let Some(oldest) = oldest {
+                clients.remove(&oldest);
+            }
+        }
+        clients.insert(key, (client.clone(), Instant::now()));
+        Ok(client)
+    }
+
+    /// Measurement policy the target's certificate must satisfy. A target that names
+    /// an instance must also present that instance_id.
+    fn target_policy(&self, target: &TargetInfo) -> Result<Option<PeerPolicy>> {
+        let policy = self.client_config.peer_policy(&target.app_id).cloned();
+        if target.instance_id.is_empty() {
+            return Ok(policy);
+        }
+        let mut policy = policy.unwrap_or_else(|| PeerPolicy {
+            app_id: target.app_id.clone(),
+            compose_hashes: Vec::new(),
+            os_image_hashes: Vec::new(),
+            instance_ids: Vec::new(),
+        });
+        if !policy.instance_ids.is_empty()
+            && !policy
+                .instance_ids
+                .iter()
+                .any(|id| id.eq_ignore_ascii_case(&target.instance_id))
+        {
+            bail!(
+                "Instance '{}' is not in the allowlist of app_id '{}'",
+                target.instance_id,
+                target.app_id
+            );
+        }
+        policy.instance_ids = vec![target.instance_id.clone()];
+        Ok(Some(policy))
+    }
+
+    /// Service alias with the given name
+    pub fn service(&self, name: &str) -> Option<&ServiceConfig> {
+        self.services
+            .iter()
+            .find(|(service, _)| service.eq_ignore_ascii_case(name))
+            .map(|(_, service)| service)
+    }
+
+    /// Route a target without an instance to one of its app's balanced instances,
+    /// skipping instances whose circuit is open. The pick counts as outstanding
+    /// until dropped.
+    pub fn balance(
+        &self,
+        target: &mut TargetInfo,
+        request: Option<&DstackRequest>,
+    ) -> Option<Pick> {
+        if !target.instance_id.is_empty() {
+            return None;
+        }
+        let balancer = self.balancers.get(&target.app_id.to_lowercase())?;
+        let breaker_config = &self.client_config.circuit_breaker;
+        let pick = balancer.pick(request, |instance_id| {
+            let candidate = TargetInfo {
+                instance_id: instance_id.to_string(),
+                ..target.clone()
+            };
+            !breaker::is_open(breaker_config, &candidate)
+        })?;
+        debug!(
+            "Balanced app_id '{}' to instance '{}'",
+            target.app_id, pick.instance_id
+        );
+        target.instance_id = pick.instance_id.clone();
+        Some(pick)
+    }
+
+    /// Name of the cookie pinning callers to an instance of `app_id`, if any
+    pub(crate) fn sticky_cookie(&self, app_id: &str) -> Option<&str> {
+        self.balancers
+            .get(&app_id.to_lowercase())
+            .and_then(Balancer::sticky_cookie)
+    }
 }
 
 pub struct ReqwestStreamReader {
@@ -33,15 +318,18 @@ pub struct ReqwestStreamReader {
     >,
     current_chunk: Option<bytes::Bytes>,
     chunk_pos: usize,
+    /// Keeps the instance outstanding until the body has been sent
+    _pick: Option<Pick>,
 }
 
 impl ReqwestStreamReader {
-    fn new(response: reqwest::Response) -> Self {
+    fn new(response: reqwest::Response, pick: Option<Pick>) -> Self {
         let stream = response.bytes_stream();
         Self {
             stream: Box::pin(stream),
             current_chunk: None,
             chunk_pos: 0,
+            _pick: pick,
         }
     }
 }
@@ -60,6 +348,7 @@ impl AsyncRead for ReqwestStreamReader {
                     let end_pos = self.chunk_pos + to_read;
                     buf.put_slice(&chunk[self.chunk_pos..end_pos]);
                     self.chunk_pos = end_pos;
+                    metrics::record_bytes(0, to_read as u64);
                     return Poll::Ready(Ok(()));
                 } else {
                     // Finished reading current chunk
@@ -92,17 +381,52 @@ impl AsyncRead for ReqwestStreamReader {
 
 pub enum ProxyResponse {
     Stream(StreamingProxyResponse),
+    Upgrade(UpgradeProxyResponse),
     Json(serde_json::Value),
+    /// Error generated by the proxy itself, sent as `{"error": ...}`
+    Error(Status, String),
+}
+
+impl ProxyResponse {
+    /// Status code sent back to the caller
+    pub fn status(&self) -> u16 {
+        match self {
+            ProxyResponse::Stream(stream) => stream.response.status().as_u16(),
+            ProxyResponse::Upgrade(_) => 101,
+            ProxyResponse::Json(_) => 200,
+            ProxyResponse::Error(status, _) => status.code,
+        }
+    }
+
+    /// Hand the balancer's pick to a proxied response, which keeps the instance
+    /// outstanding until the body or upgraded connection is done and pins the caller
+    /// to it. Responses generated by the proxy itself just drop it.
+    fn with_pick(mut self, pick: Pick) -> Self {
+        let (extra_headers, slot) = match &mut self {
+            ProxyResponse::Stream(streaming) => (&mut streaming.extra_headers, &mut streaming.pick),
+            ProxyResponse::Upgrade(upgrade) => (&mut upgrade.extra_headers, &mut upgrade.pick),
+            _ => return self,
+        };
+        if let Some(cookie) = &pick.set_cookie {
+            extra_headers.push(("set-cookie".to_string(), cookie.clone()));
+        }
+        *slot = Some(pick);
+        self
+    }
 }
 
 pub struct StreamingProxyResponse {
     response: reqwest::Response,
+    /// Headers added by the proxy itself
+    extra_headers: Vec<(String, String)>,
+    pick: Option<Pick>,
 }
 
 impl<'r> Responder<'r, 'static> for ProxyResponse {
     fn respond_to(self, request: &'r rocket::Request<'_>) -> rocket::response::Result<'static> {
         match self {
             ProxyResponse::Stream(streaming) => streaming.respond_to(request),
+            ProxyResponse::Upgrade(upgrade) => upgrade.respond_to(request),
             ProxyResponse::Json(json) => {
                 let json_string = serde_json::to_string(&json).unwrap_or_default();
                 Response::build()
@@ -110,6 +434,14 @@ impl<'r> Responder<'r, 'static> for ProxyResponse {
                     .sized_body(json_string.len(), std::io::Cursor::new(json_string))
                     .ok()
             }
+            ProxyResponse::Error(status, message) => {
+                let json_string = serde_json::json!({ "error": message }).to_string();
+                Response::build()
+                    .status(status)
+                    .header(rocket::http::ContentType::JSON)
+                    .sized_body(json_string.len(), std::io::Cursor::new(json_string))
+                    .ok()
+            }
         }
     }
 }
@@ -128,7 +460,7 @@ impl<'r> Responder<'r, 'static> for StreamingProxyResponse {
                     .map(|v| (name.to_string(), v.to_string()))
             })
             .collect();
-        let reader = ReqwestStreamReader::new(self.response);
+        let reader = ReqwestStreamReader::new(self.response, self.pick);
 
         let mut response_builder = Response::build();
 
@@ -136,11 +468,75 @@ impl<'r> Responder<'r, 'static> for StreamingProxyResponse {
         for (name, value) in headers {
             response_builder.raw_header(name, value);
         }
+        for (name, value) in self.extra_headers {
+            response_builder.raw_header_adjoin(name, value);
+        }
 
         response_builder.streamed_body(reader).ok()
     }
 }
 
+/// Response to an upgrade request that the upstream accepted with `101 Switching Protocols`
+pub struct UpgradeProxyResponse {
+    protocol: String,
+    response: reqwest::Response,
+    /// Headers added by the proxy itself
+    extra_headers: Vec<(String, String)>,
+    pick: Option<Pick>,
+}
+
+impl<'r> Responder<'r, 'static> for UpgradeProxyResponse {
+    fn respond_to(self, _: &'r rocket::Request<'_>) -> rocket::response::Result<'static> {
+        let mut response_builder = Response::build();
+
+        // Rocket sets the status and the Connection/Upgrade headers itself
+        for (name, value) in self.response.headers() {
+            if is_hop_by_hop(name.as_str()) {
+                continue;
+            }
+            if let Ok(value) = value.to_str() {
+                response_builder.raw_header(name.to_string(), value.to_string());
+            }
+        }
+        for (name, value) in self.extra_headers {
+            response_builder.raw_header_adjoin(name, value);
+        }
+
+        response_builder
+            .upgrade(
+                self.protocol,
+                UpstreamUpgrade {
+                    response: self.response,
+                    _pick: self.pick,
+                },
+            )
+            .ok()
+    }
+}
+
+/// Splices the upgraded client connection with the upgraded upstream connection
+struct UpstreamUpgrade {
+    response: reqwest::Response,
+    /// Keeps the instance outstanding until the upgraded connection closes
+    _pick: Option<Pick>,
+}
+
+#[rocket::async_trait]
+impl IoHandler for UpstreamUpgrade {
+    async fn io(self: Pin<Box<Self>>, mut io: IoStream) -> std::io::Result<()> {
+        let this = Pin::into_inner(self);
+        let mut upstream = this
+            .response
+            .upgrade()
+            .await
+            .map_err(std::io::Error::other)?;
+        let (sent, received) = tokio::io::copy_bidirectional(&mut io, &mut upstream).await?;
+        metrics::record_bytes(sent, received);
+        debug!("Upgraded connection closed: sent {sent} bytes, received {received} bytes");
+        Ok(())
+    }
+}
+
 /// Custom request guard for extracting request information we need
 pub struct DstackRequest {
     pub target_app: Option<String>,
@@ -151,6 +547,24 @@ pub struct DstackRequest {
     pub path: String,
     pub method: String,
     pub use_tls: bool,
+    /// The target was taken from the `Host` header, which is then not forwarded
+    pub routed_by_host: bool,
+    /// Protocol requested via `Upgrade` when the request asks for a connection upgrade
+    pub upgrade: Option<String>,
+    /// The caller's `x-request-id`, or a generated one that is forwarded upstream
+    pub request_id: String,
+    /// Trace context propagated by the caller
+    pub trace_context: opentelemetry::Context,
+}
+
+impl DstackRequest {
+    /// Value of the first incoming header with the given name
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.all_headers
+            .iter()
+            .find(|(n, _)| n.eq_ignore_ascii_case(name))
+            .map(|(_, value)| value.as_str())
+    }
 }
 
 #[rocket::async_trait]
@@ -159,33 +573,83 @@ impl<'r> FromRequest<'r> for DstackRequest {
 
     async fn from_request(request: &'r Request<'_>) -> request::Outcome<Self, Self::Error> {
         let headers = request.headers();
-        let target_app = headers
+        let mut target_app = headers
             .get_one("x-dstack-target-app")
             .map(|s| s.to_string());
-        let target_port = headers
+        let mut target_port = headers
             .get_one("x-dstack-target-port")
             .map(|s| s.to_string());
-        let target_instance = headers
+        let mut target_instance = headers
             .get_one("x-dstack-target-instance")
             .map(|s| s.to_string());
-        let use_tls = headers
+        let mut use_tls = headers
             .get_one("x-dstack-target-use-tls")
             .map(|s| s == "true" || s == "1")
             .unwrap_or(true);
 
-        let all_headers = headers
+        // Header-less addressing modes, only on the client proxy
+        let mut routed_by_host = false;
+        let mut path = request.uri().path().to_string();
+        let client = request
+            .rocket()
+            .state::<Arc<ClientState>>()
+            .filter(|_| target_app.is_none());
+        if let Some(client) = client {
+            match resolve_addressing(request, client) {
+                Ok(Some(addressed)) => {
+                    target_app = Some(addressed.target.app_id);
+                    target_port = Some(addressed.target.port.to_string());
+                    target_instance = Some(addressed.target.instance_id);
+                    use_tls = addressed.use_tls;
+                    routed_by_host = addressed.by_host;
+                    if let Some(stripped) = addressed.path {
+                        path = stripped;
+                    }
+                }
+                Ok(None) => {
+                    if let Some(host) = denied_host(request, client) {
+                        warn!("Denied request to non-mesh host '{host}'");
+                        return request::Outcome::Error((Status::Forbidden, ()));
+                    }
+                }
+                Err(name) => {
+                    warn!("Unknown mesh service '{name}'");
+                    return request::Outcome::Error((Status::NotFound, ()));
+                }
+            }
+        }
+
+        let mut all_headers: Vec<(String, String)> = headers
             .iter()
             .map(|h| (h.name().to_string(), h.value().to_string()))
             .collect();
 
-        let query_string = request.uri().query().map(|q| q.to_string());
+        let request_id = match headers.get_one("x-request-id") {
+            Some(id) => id.to_string(),
+            None => {
+                let id = uuid::Uuid::new_v4().to_string();
+                all_headers.push(("x-request-id".to_string(), id.clone()));
+                id
+            }
+        };
+        let trace_context = telemetry::extract_context(headers);
 
-        // Extract path from URI
-        let path = request.uri().path().to_string();
+        let query_string = request.uri().query().map(|q| q.to_string());
 
         // Extract HTTP method
         let method = request.method().to_string();
 
+        // Detect upgrade requests (e.g. WebSocket handshakes)
+        let wants_upgrade = headers.get("connection").any(|value| {
+            value
+                .split(',')
+                .any(|token| token.trim().eq_ignore_ascii_case("upgrade"))
+        });
+        let upgrade = headers
+            .get_one("upgrade")
+            .filter(|_| wants_upgrade)
+            .map(|s| s.to_string());
+
         request::Outcome::Success(DstackRequest {
             target_app,
             target_port,
@@ -195,34 +659,40 @@ impl<'r> FromRequest<'r> for DstackRequest {
             path,
             method,
             use_tls,
+            routed_by_host,
+            upgrade,
+            request_id,
+            trace_context,
         })
     }
 }
 
 /// Run client proxy with configuration from main figment
-pub async fn run_client_proxy(main_figment: &Figment, config: &Config) -> Result<()> {
-    // Create mTLS-enabled HTTP client
-    let http_client = create_mtls_client(config).context("Failed to create mTLS HTTP client")?;
-
-    let state = ClientState {
-        gateway_domain: config.dstack.gateway_domain.clone(),
-        http_client,
-    };
-
+pub async fn run_client_proxy(
+    main_figment: &Figment,
+    config: &ClientConfig,
+    state: Arc<ClientState>,
+    readiness: Arc<Readiness>,
+    shutdown: CancellationToken,
+) -> Result<()> {
     info!("Client proxy starting with Figment configuration");
 
+    // Rocket serves plain HTTP requests on a private unix socket, behind the listener
+    // on the client address that also takes `CONNECT`s and h2c
+    let socket_dir = tempfile::Builder::new()
+        .prefix("dstack-mesh-client")
+        .tempdir()
+        .context("Failed to create client proxy socket directory")?;
+    let socket = socket_dir.path().join("rocket.sock");
+
     // Create Rocket figment for client service using the client section
-    let figment = Figment::new()
-        .merge(rocket::Config::default())
-        .merge(Serialized::defaults(
-            main_figment
-                .find_value("client")
-                .context("client section not found")?,
-        ));
+    let figment = rocket_figment(main_figment, "client")?
+        .merge(("address", format!("unix:{}", socket.display())));
 
     // Launch Rocket server
-    let _rocket = rocket::custom(figment)
-        .manage(state)
+    let rocket = rocket::custom(figment)
+        .manage(state.clone())
+        .manage(readiness)
         .mount(
             "/",
             routes![
@@ -232,12 +702,15 @@ pub async fn run_client_proxy(main_figment: &Figment, config: &Config) -> Result
                 proxy_patch_handler,
                 proxy_delete_handler,
                 health_handler,
+                client_ready_handler,
+                client_metrics_handler,
             ],
-        )
-        .launch()
-        .await
-        .map_err(|e| anyhow::anyhow!("Rocket launch error: {}", e))?;
-
+        );
+    let addr = SocketAddr::new(config.address, config.port);
+    tokio::try_join!(
+        launch_rocket(rocket, shutdown.clone()),
+        mux::run_client_listener(addr, socket, state, shutdown),
+    )?;
     Ok(())
 }
 
@@ -246,7 +719,7 @@ pub async fn run_client_proxy(main_figment: &Figment, config: &Config) -> Result
 async fn proxy_get_handler(
     _path: Segments<'_, Path>,
     request: DstackRequest,
-    state: &State<ClientState>,
+    state: &State<Arc<ClientState>>,
 ) -> Result<ProxyResponse, Status> {
     proxy_request(&request, state, None).await
 }
@@ -257,7 +730,7 @@ async fn proxy_post_handler(
     _path: Segments<'_, Path>,
     request: DstackRequest,
     body: Data<'_>,
-    state: &State<ClientState>,
+    state: &State<Arc<ClientState>>,
 ) -> Result<ProxyResponse, Status> {
     proxy_request(&request, state, Some(body)).await
 }
@@ -268,7 +741,7 @@ async fn proxy_put_handler(
     _path: Segments<'_, Path>,
     request: DstackRequest,
     body: Data<'_>,
-    state: &State<ClientState>,
+    state: &State<Arc<ClientState>>,
 ) -> Result<ProxyResponse, Status> {
     proxy_request(&request, state, Some(body)).await
 }
@@ -279,7 +752,7 @@ async fn proxy_patch_handler(
     _path: Segments<'_, Path>,
     request: DstackRequest,
     body: Data<'_>,
-    state: &State<ClientState>,
+    state: &State<Arc<ClientState>>,
 ) -> Result<ProxyResponse, Status> {
     proxy_request(&request, state, Some(body)).await
 }
@@ -289,7 +762,7 @@ async fn proxy_patch_handler(
 async fn proxy_delete_handler(
     _path: Segments<'_, Path>,
     request: DstackRequest,
-    state: &State<ClientState>,
+    state: &State<Arc<ClientState>>,
 ) -> Result<ProxyResponse, Status> {
     proxy_request(&request, state, None).await
 }
@@ -300,11 +773,53 @@ fn health_handler() -> Status {
     Status::Ok
 }
 
+/// Matches requests addressed to the proxy itself rather than to a mesh target, so
+/// that e.g. `/ready` on a target is still proxied
+pub struct LocalRequest;
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for LocalRequest {
+    type Error = ();
+
+    async fn from_request(request: &'r Request<'_>) -> request::Outcome<Self, Self::Error> {
+        // Anything the proxy would route, or refuse, is left to the proxy handlers
+        let addressed = request.headers().contains("x-dstack-target-app")
+            || request
+                .rocket()
+                .state::<Arc<ClientState>>()
+                .is_some_and(|state| {
+                    !matches!(resolve_addressing(request, state), Ok(None))
+                        || denied_host(request, state).is_some()
+                });
+        if addressed {
+            request::Outcome::Forward(Status::NotFound)
+        } else {
+            request::Outcome::Success(LocalRequest)
+        }
+    }
+}
+
+/// Readiness probe
+#[get("/ready?<verbose>")]
+async fn client_ready_handler(
+    _local: LocalRequest,
+    verbose: Option<bool>,
+    readiness: &State<Arc<Readiness>>,
+) -> Either<Status, (Status, Json<ready::Report>)> {
+    ready::respond(readiness, verbose).await
+}
+
+/// Prometheus metrics
+#[get("/metrics")]
+fn client_metrics_handler(_local: LocalRequest) -> Result<(ContentType, String), Status> {
+    metrics::render()
+}
+
 /// Proxy request to dstack.sock when no target headers are present
 async fn proxy_to_dstack_sock(
     request: &DstackRequest,
     body: Option<Data<'_>>,
-    state: &State<ClientState>,
+    state: &ClientRuntime,
 ) -> Result<ProxyResponse, Status> {
     let path = request.path.trim_start_matches('/');
 
@@ -373,7 +888,11 @@ async fn proxy_to_dstack_sock(
         }
     }
     match request_builder.send().await {
-        Ok(response) => Ok(ProxyResponse::Stream(StreamingProxyResponse { response })),
+        Ok(response) => Ok(ProxyResponse::Stream(StreamingProxyResponse {
+            response,
+            extra_headers: Vec::new(),
+            pick: None,
+        })),
         Err(e) => {
             tracing::error!("Request to dstack.sock failed: {}", e);
             Err(Status::BadGateway)
@@ -383,11 +902,15 @@ async fn proxy_to_dstack_sock(
 
 async fn proxy_request(
     request: &DstackRequest,
-    state: &State<ClientState>,
+    state: &ClientState,
     body: Option<Data<'_>>,
 ) -> Result<ProxyResponse, Status> {
+    // Requests finish on the snapshot they started with, even if a reload happens meanwhile
+    let runtime = state.runtime();
+    let state = &*runtime;
+
     // Extract target info from headers
-    let target = match extract_target_info(request) {
+    let mut target = match extract_target_info(request) {
         Some(t) => t,
         None => {
             debug!("Missing x-dstack-target-app header, delegating to dstack.sock");
@@ -397,7 +920,50 @@ async fn proxy_request(
 
     // Validate connection target before proceeding
     validate_connection_target(&target)?;
+    let pick = state.balance(&mut target, Some(request));
+
+    let span = info_span!(
+        "proxy_request",
+        otel.kind = "client",
+        target_app = %target.app_id,
+        target_port = target.port,
+        target_instance = %target.instance_id,
+        http.method = %request.method,
+        http.status_code = field::Empty,
+        request_id = %request.request_id,
+    );
+    span.set_parent(request.trace_context.clone());
+
+    let _active = stats::active(&target);
+    let started = Instant::now();
+    let result = proxy_to_target(request, state, &target, body)
+        .instrument(span.clone())
+        .await;
+    let status = match &result {
+        Ok(response) => response.status(),
+        Err(status) => status.code,
+    };
+    span.record("http.status_code", status);
+    metrics::record_request(
+        &target.app_id,
+        target.port,
+        &request.method,
+        status,
+        started.elapsed(),
+    );
+    match pick {
+        Some(pick) => result.map(|response| response.with_pick(pick)),
+        None => result,
+    }
+}
 
+/// Forward a request to a mesh target through the gateway
+async fn proxy_to_target(
+    request: &DstackRequest,
+    state: &ClientRuntime,
+    target: &TargetInfo,
+    body: Option<Data<'_>>,
+) -> Result<ProxyResponse, Status> {
     // Build target URL
     let url = {
         let path = request.path.trim_start_matches('/');
@@ -405,35 +971,114 @@ async fn proxy_request(
             Some(query) => format!("{}?{}", path, query),
             None => path.to_string(),
         };
-        let gateway_domain = state.gateway_domain.trim_end_matches("/");
-
-        if gateway_domain.starts_with("fixed/") {
-            let domain = gateway_domain.trim_start_matches("fixed/");
-            format!("https://{domain}/{full_path}")
-        } else {
-            let id = if target.instance_id.is_empty() {
-                &target.app_id
-            } else {
-                &target.instance_id
-            };
-            let port = &target.port;
-            if request.use_tls {
-                format!("https://{id}-{port}s.{gateway_domain}/{full_path}")
-            } else {
-                format!("https://{id}-{port}.{gateway_domain}/{full_path}")
-            }
-        }
+        let authority = state.upstream_authority(target, request.use_tls);
+        format!("https://{authority}/{full_path}")
     };
 
-    // Create HTTP client with TLS config
-    // Note: For now using simple HTTP client, would need to configure mTLS properly
     let http_method = match reqwest::Method::from_bytes(request.method.as_bytes()) {
         Ok(m) => m,
         Err(_) => return Err(Status::MethodNotAllowed),
     };
 
+    debug!(
+        "Proxying request to app_id '{}' at URL: {}",
+        target.app_id, url
+    );
+
+    let policy = state.target_policy(target).map_err(|err| {
+        warn!("{err:#}");
+        Status::Forbidden
+    })?;
+
+    // Only idempotent requests with a body small enough to replay are retried
+    let limit = state.client_config.body_limit(&target.app_id);
+    state.retry.record_request();
+    let retryable = request.upgrade.is_none()
+        && state
+            .retry
+            .is_retryable(&request.method, request.header("idempotency-key").is_some());
+    let mut body = UpstreamBody::prepare(request, body, retryable, &state.retry, limit).await?;
+    let retryable = retryable && body.is_replayable();
+
+    let breaker_config = &state.client_config.circuit_breaker;
+    let mut attempt = 1;
+    loop {
+        let permit = match breaker::acquire(breaker_config, target) {
+            Ok(permit) => permit,
+            Err(open) => {
+                debug!("{open}");
+                metrics::record_upstream_error(&target.app_id, "circuit_open");
+                return Ok(ProxyResponse::Error(
+                    Status::ServiceUnavailable,
+                    open.to_string(),
+                ));
+            }
+        };
+        let request_builder = build_upstream_request(request, state, target, &url, &http_method)?;
+        let result = body.send(request_builder, limit).await?;
+        match &result {
+            Ok(response)
+                if !breaker_config
+                    .failure_statuses
+                    .contains(&response.status().as_u16()) =>
+            {
+                permit.success()
+            }
+            _ => permit.failure(),
+        }
+        let reason = match &result {
+            Ok(response) => RetryReason::Status(response.status().as_u16()),
+            Err(e) => {
+                tracing::error!("mTLS request to app_id '{}' failed: {}", target.app_id, e);
+                let kind = metrics::upstream_error_kind(e);
+                metrics::record_upstream_error(&target.app_id, kind);
+                stats::record_error(&target.app_id, target.port, e.to_string());
+                RetryReason::Error(kind)
+            }
+        };
+        if retryable && state.retry.should_retry(&target.app_id, attempt, reason) {
+            let backoff = state.retry.backoff(attempt);
+            info!(
+                "Retrying request to app_id '{}' after {reason:?} in {backoff:?} (attempt {})",
+                target.app_id,
+                attempt + 1
+            );
+            tokio::time::sleep(backoff).await;
+            attempt += 1;
+            continue;
+        }
+
+        let response = result.map_err(|_| Status::BadGateway)?;
+        if request.use_tls {
+            // The handshake already verified the peer; double check what we actually got
+            if let Err(err) = verify_response_security(&response, target, policy.as_ref())
+                .context("Failed to verify response security")
+            {
+                warn!("Failed to verify response security: {err:?}");
+                metrics::record_upstream_error(&target.app_id, "app_id_mismatch");
+                stats::record_error(&target.app_id, target.port, format!("{err:#}"));
+                return Err(Status::BadGateway);
+            }
+        }
+        return Ok(into_proxy_response(request, response));
+    }
+}
+
+/// Build one attempt of an upstream request, without its body
+fn build_upstream_request(
+    request: &DstackRequest,
+    state: &ClientRuntime,
+    target: &TargetInfo,
+    url: &str,
+    http_method: &reqwest::Method,
+) -> Result<reqwest::RequestBuilder, Status> {
     let mut request_builder = if request.use_tls {
-        state.http_client.request(http_method, &url)
+        // The client's verifier rejects the handshake unless the peer carries target.app_id
+        let mtls_client = state.mtls_client(target).map_err(|err| {
+            tracing::error!("Failed to create mTLS client: {err:?}");
+            Status::InternalServerError
+        })?;
+        mtls_client.request(http_method.clone(), url)
     } else {
         // For non-TLS, create a separate client without mTLS
         let non_tls_client = Client::builder()
@@ -442,84 +1087,428 @@ async fn proxy_request(
             .redirect(Policy::none())
             .build()
             .map_err(|_| Status::InternalServerError)?;
-        non_tls_client.request(http_method, &url)
+        non_tls_client.request(http_method.clone(), url)
     };
 
-    // Handle body for methods that support it with streaming
-    if let Some(body_data) = body {
-        // Use a reasonable limit to prevent OOM (100MB)
-        const MAX_BODY_SIZE: u64 = 100 * 1024 * 1024;
-        let mut reader = body_data.open(rocket::data::ByteUnit::Byte(MAX_BODY_SIZE));
+    // Copy relevant headers (excluding routing headers)
+    let sticky_cookie = state.sticky_cookie(&target.app_id);
+    request_builder = forward_headers(request_builder, request, |name| {
+        name.starts_with("x-dstack-target-")
+            || (request.routed_by_host && name.eq_ignore_ascii_case("host"))
+            || (sticky_cookie.is_some() && name.eq_ignore_ascii_case("cookie"))
+    });
+    if let Some(sticky_cookie) = sticky_cookie {
+        // The sticky cookie is set by the proxy, not by the target, so it stays here
+        let cookies = request
+            .all_headers
+            .iter()
+            .filter(|(name, _)| name.eq_ignore_ascii_case("cookie"));
+        for (_, value) in cookies {
+            if let Some(value) = strip_cookie(value, sticky_cookie) {
+                request_builder = request_builder.header(reqwest::header::COOKIE, value);
+            }
+        }
+    }
+    Ok(telemetry::inject_context(request_builder))
+}
 
-        // Read in chunks to avoid OOM
-        let mut buffer = Vec::new();
-        const CHUNK_SIZE: usize = 64 * 1024; // 64KB chunks
-        let mut chunk = vec![0u8; CHUNK_SIZE];
+/// Body of an upstream request, buffered when the request may be retried
+enum UpstreamBody<'r> {
+    Empty,
+    Buffered(Bytes),
+    /// Streamed once; taken by the first attempt
+    Stream(Option<Data<'r>>),
+}
 
-        loop {
-            match reader.read(&mut chunk).await {
-                Ok(0) => break, // EOF
-                Ok(n) => {
-                    buffer.extend_from_slice(&chunk[..n]);
-                    // Check if we're getting too large
-                    if buffer.len() > MAX_BODY_SIZE as usize {
-                        return Err(Status::PayloadTooLarge);
-                    }
-                }
-                Err(_) => return Err(Status::BadRequest),
+impl<'r> UpstreamBody<'r> {
+    /// Buffer the body of a retryable request if its declared length fits the retry
+    /// buffer. Bodies of unknown length are streamed.
+    async fn prepare(
+        request: &DstackRequest,
+        body: Option<Data<'r>>,
+        retryable: bool,
+        retry: &RetryPolicy,
+        limit: ByteUnit,
+    ) -> Result<Self, Status> {
+        let Some(body) = body else {
+            return Ok(Self::Empty);
+        };
+        let content_length = request
+            .header("content-length")
+            .and_then(|len| len.parse::<u64>().ok());
+        match content_length {
+            Some(len) if retryable && len <= retry.max_body_size() && len <= limit.as_u64() => {
+                let bytes = body
+                    .open(ByteUnit::Byte(len))
+                    .into_bytes()
+                    .await
+                    .map_err(|e| {
+                        warn!("Failed to read request body: {e}");
+                        Status::BadRequest
+                    })?;
+                Ok(Self::Buffered(Bytes::from(bytes.into_inner())))
             }
+            _ => Ok(Self::Stream(Some(body))),
         }
+    }
 
-        request_builder = request_builder.body(buffer);
+    fn is_replayable(&self) -> bool {
+        !matches!(self, Self::Stream(_))
     }
 
-    // Copy relevant headers (excluding routing headers)
+    async fn send(
+        &mut self,
+        request_builder: reqwest::RequestBuilder,
+        limit: ByteUnit,
+    ) -> Result<reqwest::Result<reqwest::Response>, Status> {
+        match self {
+            Self::Empty => Ok(request_builder.send().await),
+            Self::Buffered(bytes) => {
+                metrics::record_bytes(bytes.len() as u64, 0);
+                Ok(request_builder.body(bytes.clone()).send().await)
+            }
+            Self::Stream(body) => send_streaming(request_builder, body.take(), limit).await,
+        }
+    }
+}
+
+/// Copy the incoming request headers onto an upstream request.
+///
+/// Hop-by-hop headers and those matched by `skip` are dropped; upgrade requests keep
+/// their `Connection`/`Upgrade` semantics.
+pub(crate) fn forward_headers(
+    mut request_builder: reqwest::RequestBuilder,
+    request: &DstackRequest,
+    skip: impl Fn(&str) -> bool,
+) -> reqwest::RequestBuilder {
     for (name, value) in &request.all_headers {
-        if !name.starts_with("x-dstack-target-") {
+        if !is_hop_by_hop(name) && !skip(name) {
             request_builder = request_builder.header(name, value);
         }
     }
+    if let Some(protocol) = &request.upgrade {
+        request_builder = request_builder
+            .header(reqwest::header::CONNECTION, "upgrade")
+            .header(reqwest::header::UPGRADE, protocol);
+    }
+    request_builder
+}
 
-    debug!(
-        "Proxying request to app_id '{}' at URL: {}",
-        target.app_id, url
-    );
+/// Send an upstream request, streaming `body` into it when present.
+///
+/// The body is fed concurrently with the request so reqwest can apply backpressure.
+/// Errors reading the incoming body take precedence over the upstream result.
+pub(crate) async fn send_streaming(
+    request_builder: reqwest::RequestBuilder,
+    body: Option<Data<'_>>,
+    limit: ByteUnit,
+) -> Result<reqwest::Result<reqwest::Response>, Status> {
+    let Some(body) = body else {
+        return Ok(request_builder.send().await);
+    };
 
-    // Execute request
-    match request_builder.send().await {
-        Ok(response) => {
-            if request.use_tls {
-                // TODO: It should be verified before sending the request. But reqwest doesn't support it.
-                if let Err(err) = verify_response_security(&response, &target)
-                    .context("Failed to verify response security")
-                {
-                    warn!("Failed to verify response security: {err:?}");
-                    return Err(Status::BadGateway);
+    let (tx, rx) = mpsc::channel(BODY_CHANNEL_CAPACITY);
+    let stream = futures_util::stream::unfold(rx, |mut rx| async move {
+        rx.recv().await.map(|chunk| (chunk, rx))
+    });
+    let request_builder = request_builder.body(reqwest::Body::wrap_stream(stream));
+    let (pumped, result) = tokio::join!(pump_request_body(body, limit, tx), request_builder.send());
+    pumped?;
+    Ok(result)
+}
+
+/// Turn an upstream response into a streamed response, or an upgrade if the
+/// upstream switched protocols
+pub(crate) fn into_proxy_response(
+    request: &DstackRequest,
+    response: reqwest::Response,
+) -> ProxyResponse {
+    if let Some(protocol) = &request.upgrade {
+        if response.status() == reqwest::StatusCode::SWITCHING_PROTOCOLS {
+            debug!("Upstream accepted '{protocol}' upgrade");
+            return ProxyResponse::Upgrade(UpgradeProxyResponse {
+                protocol: protocol.clone(),
+                response,
+                extra_headers: Vec::new(),
+                pick: None,
+            });
+        }
+    }
+    // Return the response directly for streaming - no buffering!
+    ProxyResponse::Stream(StreamingProxyResponse {
+        response,
+        extra_headers: Vec::new(),
+        pick: None,
+    })
+}
+
+/// Read an incoming request body chunk by chunk and feed it to a streaming reqwest body.
+///
+/// The bounded channel provides backpressure: reading stalls until the upstream
+/// connection has consumed earlier chunks. Exceeding `limit` aborts the upstream body.
+async fn pump_request_body(
+    body: Data<'_>,
+    limit: ByteUnit,
+    tx: mpsc::Sender<std::io::Result<Bytes>>,
+) -> Result<(), Status> {
+    // Open one byte past the limit so that oversized bodies can be told apart
+    let mut reader = body.open(ByteUnit::Byte(limit.as_u64().saturating_add(1)));
+    let mut total: u64 = 0;
+
+    loop {
+        let mut chunk = BytesMut::with_capacity(BODY_CHUNK_SIZE);
+        match reader.read_buf(&mut chunk).await {
+            Ok(0) => return Ok(()),
+            Ok(n) => {
+                total += n as u64;
+                if total > limit.as_u64() {
+                    warn!("Request body exceeds limit of {limit}");
+                    let _ = tx
+                        .send(Err(std::io::Error::other("request body too large")))
+                        .await;
+                    return Err(Status::PayloadTooLarge);
                 }
+                metrics::record_bytes(n as u64, 0);
+                if tx.send(Ok(chunk.freeze())).await.is_err() {
+                    // Upstream stopped reading the body, e.g. it already responded
+                    debug!("Upstream closed the request body after {total} bytes");
+                    return Ok(());
+                }
+            }
+            Err(e) => {
+                warn!("Failed to read request body: {e}");
+                let _ = tx.send(Err(e)).await;
+                return Err(Status::BadRequest);
             }
-            // Return the response directly for streaming - no buffering!
-            Ok(ProxyResponse::Stream(StreamingProxyResponse { response }))
         }
-        Err(e) => {
-            tracing::error!("mTLS request to app_id '{}' failed: {}", target.app_id, e);
-            Err(Status::BadGateway)
+    }
+}
+
+pub(crate) fn is_hop_by_hop(name: &str) -> bool {
+    HOP_BY_HOP_HEADERS
+        .iter()
+        .any(|h| h.eq_ignore_ascii_case(name))
+}
+
+/// Target addressed without the `x-dstack-target-*` headers
+struct Addressed {
+    target: TargetInfo,
+    use_tls: bool,
+    /// Taken from the `Host` header
+    by_host: bool,
+    /// Request path with the routing prefix stripped
+    path: Option<String>,
+}
+
+/// Resolve a service alias, a `<name>.mesh` or `<app_id>-<port>.mesh` Host, a
+/// `<app_id>-<port>[s].mesh.local` Host, a forward-proxied `<app_id>-<port>.dstack`
+/// Host or a `/_mesh/<app_id>/<port>/` path prefix, in that order. Fails with the name
+/// of an unknown service.
+fn resolve_addressing(
+    request: &Request<'_>,
+    state: &ClientState,
+) -> Result<Option<Addressed>, String> {
+    let runtime = state.runtime();
+    if let Some(name) = request.headers().get_one("x-dstack-target-service") {
+        let Some(service) = runtime.service(name) else {
+            return Err(name.to_string());
+        };
+        return Ok(Some(Addressed {
+            target: service.target(),
+            use_tls: service.tls,
+            by_host: false,
+            path: None,
+        }));
+    }
+    if let Some(host) = request.headers().get_one("host") {
+        if let Some(name) = service_from_host(host) {
+            // Same names as on the CONNECT and SOCKS listeners
+            let use_tls = runtime.service(&name).map_or(true, |service| service.tls);
+            let Some(target) = target_from_mesh_name(host, &runtime) else {
+                return Err(name);
+            };
+            return Ok(Some(Addressed {
+                target,
+                use_tls,
+                by_host: true,
+                path: None,
+            }));
         }
     }
+    if let Some((target, use_tls)) = request
+        .headers()
+        .get_one("host")
+        .and_then(target_from_mesh_host)
+    {
+        return Ok(Some(Addressed {
+            target,
+            use_tls,
+            by_host: true,
+            path: None,
+        }));
+    }
+    let forward_proxy = state.runtime().client_config.forward_proxy.enabled;
+    if let Some(target) = request
+        .headers()
+        .get_one("host")
+        .filter(|_| forward_proxy)
+        .and_then(target_from_dstack_host)
+    {
+        // Forward-proxy clients speak plain HTTP to us; the mesh hop is always mTLS
+        return Ok(Some(Addressed {
+            target,
+            use_tls: true,
+            by_host: true,
+            path: None,
+        }));
+    }
+    if let Some((target, path)) = target_from_mesh_path(request.uri().path().as_str()) {
+        return Ok(Some(Addressed {
+            target,
+            use_tls: true,
+            by_host: false,
+            path: Some(path),
+        }));
+    }
+    Ok(None)
+}
+
+/// Host (and optional port) of `gateway_domain` that routes to the given target
+pub(crate) fn gateway_authority(
+    gateway_domain: &str,
+    target: &TargetInfo,
+    use_tls: bool,
+) -> String {
+    let gateway_domain = gateway_domain.trim_end_matches("/");
+
+    if gateway_domain.starts_with("fixed/") {
+        gateway_domain.trim_start_matches("fixed/").to_string()
+    } else {
+        let id = if target.instance_id.is_empty() {
+            &target.app_id
+        } else {
+            &target.instance_id
+        };
+        let port = &target.port;
+        if use_tls {
+            format!("{id}-{port}s.{gateway_domain}")
+        } else {
+            format!("{id}-{port}.{gateway_domain}")
+        }
+    }
+}
+
+/// Target of a `<app_id>-<port>[s].mesh.local[:port]` host, and whether the `s`
+/// suffix asks for mTLS
+fn target_from_mesh_host(host: &str) -> Option<(TargetInfo, bool)> {
+    let host = host_name(host).to_lowercase();
+    let (app_id, port) = host.strip_suffix(".mesh.local")?.rsplit_once('-')?;
+    let (port, use_tls) = match port.strip_suffix('s') {
+        Some(port) => (port, true),
+        None => (port, false),
+    };
+    let target = TargetInfo {
+        app_id: app_id.to_string(),
+        instance_id: String::new(),
+        port: port.parse().ok()?,
+    };
+    Some((target, use_tls))
+}
+
+/// Target of a forward-proxied `<app_id>-<port>.dstack[:port]` destination
+pub(crate) fn target_from_dstack_host(host: &str) -> Option<TargetInfo> {
+    let host = host_name(host).to_lowercase();
+    let (app_id, port) = host.strip_suffix(".dstack")?.rsplit_once('-')?;
+    Some(TargetInfo {
+        app_id: app_id.to_string(),
+        instance_id: String::new(),
+        port: port.parse().ok()?,
+    })
+}
+
+/// Destination host of a request that forward-proxy mode refuses: neither a mesh
+/// target nor one of `forward_proxy.local_hosts`
+fn denied_host(request: &Request<'_>, state: &ClientState) -> Option<String> {
+    let runtime = state.runtime();
+    let forward_proxy = &runtime.client_config.forward_proxy;
+    if !forward_proxy.enabled {
+        return None;
+    }
+    let host = host_name(request.headers().get_one("host")?).to_lowercase();
+    let local = forward_proxy
+        .local_hosts
+        .iter()
+        .any(|local| local.eq_ignore_ascii_case(&host));
+    (!local).then_some(host)
+}
+
+/// Host name of a `Host` header or authority, without the port
+fn host_name(host: &str) -> &str {
+    if let Some(rest) = host.strip_prefix('[') {
+        return rest.split_once(']').map_or(rest, |(ip, _)| ip);
+    }
+    host.split_once(':').map_or(host, |(name, _)| name)
+}
+
+/// Target of a `/_mesh/<app_id>/<port>/rest/of/path` path, and the path to forward
+fn target_from_mesh_path(path: &str) -> Option<(TargetInfo, String)> {
+    let rest = path.strip_prefix("/_mesh/")?;
+    let mut segments = rest.splitn(3, '/');
+    let app_id = segments.next().filter(|app_id| !app_id.is_empty())?;
+    let port = segments.next()?.parse().ok()?;
+    let target = TargetInfo {
+        app_id: app_id.to_string(),
+        instance_id: String::new(),
+        port,
+    };
+    Some((target, format!("/{}", segments.next().unwrap_or_default())))
+}
+
+/// Target of a `<name>.mesh` service alias or an `<app_id>-<port>.mesh` host
+pub(crate) fn target_from_mesh_name(host: &str, runtime: &ClientRuntime) -> Option<TargetInfo> {
+    let name = service_from_host(host)?;
+    if let Some(service) = runtime.service(&name) {
+        return Some(service.target());
+    }
+    let (app_id, port) = name.rsplit_once('-')?;
+    Some(TargetInfo {
+        app_id: app_id.to_string(),
+        instance_id: String::new(),
+        port: port.parse().ok()?,
+    })
+}
+
+/// Service name of a `<name>.mesh[:port]` host
+pub(crate) fn service_from_host(host: &str) -> Option<String> {
+    let name = host_name(host)
+        .to_lowercase()
+        .strip_suffix(".mesh")?
+        .to_string();
+    (!name.is_empty()).then_some(name)
 }
 
 fn extract_target_info(request: &DstackRequest) -> Option<TargetInfo> {
+    target_from_headers(
+        request.target_app.as_deref(),
+        request.target_port.as_deref(),
+        request.target_instance.as_deref(),
+    )
+}
+
+/// Build target info from the values of the `x-dstack-target-*` headers
+pub(crate) fn target_from_headers(
+    app: Option<&str>,
+    port: Option<&str>,
+    instance: Option<&str>,
+) -> Option<TargetInfo> {
     // Extract app_id (required)
-    let app_id = request.target_app.as_ref()?.clone();
+    let app_id = app?.to_string();
 
     // Extract port (optional, default 443)
-    let port = request
-        .target_port
-        .as_ref()
-        .and_then(|v| v.parse().ok())
-        .unwrap_or(443);
+    let port = port.and_then(|v| v.parse().ok()).unwrap_or(443);
 
     // Extract instance (optional)
-    let instance = request.target_instance.clone().unwrap_or_default();
+    let instance = instance.unwrap_or_default().to_string();
 
     Some(TargetInfo {
         app_id,
@@ -528,27 +1517,18 @@ fn extract_target_info(request: &DstackRequest) -> Option<TargetInfo> {
     })
 }
 
-/// Create an HTTP client configured with mTLS using certificates from files
-fn create_mtls_client(config: &Config) -> Result<Client> {
-    use fs_err as fs;
-    let key_pem = fs::read_to_string(&config.tls.key_file).context("Failed to read key file")?;
-    let cert_pem = fs::read_to_string(&config.tls.cert_file).context("Failed to read cert file")?;
-    let ca_pem = fs::read_to_string(&config.tls.ca_file).context("Failed to read CA file")?;
-
-    // Try using the full certificate chain instead of just the leaf certificate
-    let identity_pem = format!("{}\n{}", cert_pem, key_pem);
-    let identity = reqwest::Identity::from_pem(identity_pem.as_bytes())?;
-    let ca = reqwest::Certificate::from_pem(ca_pem.as_bytes())?;
+/// Create an HTTP client configured with mTLS that only accepts peers with `expected_app_id`
+/// whose measurements satisfy `policy`
+fn create_mtls_client(
+    tls: &TlsMaterial,
+    expected_app_id: &str,
+    policy: Option<&PeerPolicy>,
+) -> Result<Client> {
+    let tls_config = tls.client_config(expected_app_id, policy)?;
     let client = Client::builder()
-        .use_rustls_tls() // Force rustls backend
-        .identity(identity)
+        .use_preconfigured_tls(tls_config)
         .tls_info(true)
         .https_only(true)
-        .danger_accept_invalid_hostnames(true)
-        .danger_accept_invalid_certs(false)
-        .tls_built_in_root_certs(false)
-        .tls_built_in_webpki_certs(false)
-        .add_root_certificate(ca)
         .redirect(Policy::none())
         .hickory_dns(true)
         .build()
@@ -557,19 +1537,28 @@ fn create_mtls_client(config: &Config) -> Result<Client> {
 }
 
 /// Validate that we should connect to the specified target
-fn validate_connection_target(target: &TargetInfo) -> Result<(), Status> {
+pub(crate) fn validate_connection_target(target: &TargetInfo) -> Result<(), Status> {
     // Ensure app_id is present and valid
     if target.app_id.is_empty() {
         tracing::error!("Target app_id cannot be empty");
         return Err(Status::BadRequest);
     }
 
-    // Validate app_id format (should be hex string for dstack)
-    if !target.app_id.chars().all(|c| c.is_ascii_hexdigit()) {
+    // dstack app and instance ids are hex strings. Anything else can never pass the
+    // handshake, and would only grow the per-target caches.
+    if !is_hex_id(&target.app_id) {
         warn!(
             "Target app_id '{}' is not in expected hex format",
             target.app_id
         );
+        return Err(Status::BadRequest);
+    }
+    if !target.instance_id.is_empty() && !is_hex_id(&target.instance_id) {
+        warn!(
+            "Target instance_id '{}' is not in expected hex format",
+            target.instance_id
+        );
+        return Err(Status::BadRequest);
     }
     debug!(
         "Validated mTLS connection target - app_id: {}, port: {}, instance: '{}'",
@@ -578,8 +1567,16 @@ fn validate_connection_target(target: &TargetInfo) -> Result<(), Status> {
     Ok(())
 }
 
+pub(crate) fn is_hex_id(id: &str) -> bool {
+    id.len() <= MAX_ID_LEN && id.chars().all(|c| c.is_ascii_hexdigit())
+}
+
 /// Verify response security and log connection info
-fn verify_response_security(response: &reqwest::Response, target: &TargetInfo) -> Result<()> {
+fn verify_response_security(
+    response: &reqwest::Response,
+    target: &TargetInfo,
+    policy: Option<&PeerPolicy>,
+) -> Result<()> {
     debug!(
         "mTLS connection established successfully - app_id: {}, port: {}, status: {}",
         target.app_id,
@@ -594,19 +1591,138 @@ fn verify_response_security(response: &reqwest::Response, target: &TargetInfo) -
         bail!("No peer certificate in response");
     };
 
-    let (_, parsed_cert) =
-        x509_parser::parse_x509_certificate(cert).context("Failed to parse certificate")?;
-    let app_id = parsed_cert
-        .get_app_id()
-        .context("Failed to get app id")?
-        .context("Missing app id in server certificate")?;
-    let app_id = hex::encode(app_id);
-    if app_id.to_lowercase() != target.app_id.to_lowercase() {
-        bail!(
-            "Server app_id mismatch: expected '{}', got '{}'",
-            target.app_id,
-            app_id
+    verify_peer(cert, &target.app_id, policy)?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn target(target: Option<TargetInfo>) -> Option<(String, String, u16)> {
+        target.map(|t| (t.app_id, t.instance_id, t.port))
+    }
+
+    #[test]
+    fn host_name_strips_port_and_brackets() {
+        assert_eq!(host_name("app-80.mesh.local"), "app-80.mesh.local");
+        assert_eq!(host_name("app-80.mesh.local:8091"), "app-80.mesh.local");
+        assert_eq!(host_name("[::1]:8091"), "::1");
+        assert_eq!(host_name("[::1]"), "::1");
+    }
+
+    #[test]
+    fn parses_mesh_local_hosts() {
+        let parse = |host| {
+            target_from_mesh_host(host).map(|(t, tls)| (t.app_id, t.instance_id, t.port, tls))
+        };
+        assert_eq!(
+            parse("abc123-8080.mesh.local"),
+            Some(("abc123".into(), "".into(), 8080, false))
+        );
+        assert_eq!(
+            parse("ABC123-443s.mesh.local:80"),
+            Some(("abc123".into(), "".into(), 443, true))
         );
+        assert_eq!(parse("abc123-8080.mesh"), None);
+        assert_eq!(parse("abc123.mesh.local"), None);
+        assert_eq!(parse("abc123-http.mesh.local"), None);
+        assert_eq!(parse("abc123-70000.mesh.local"), None);
+        assert_eq!(parse("abc123-8080.mesh.local.evil.com"), None);
+    }
+
+    #[test]
+    fn parses_mesh_paths() {
+        let parse = |path| target_from_mesh_path(path).map(|(t, path)| (t.app_id, t.port, path));
+        assert_eq!(
+            parse("/_mesh/abc123/8080/api/items"),
+            Some(("abc123".into(), 8080, "/api/items".into()))
+        );
+        assert_eq!(
+            parse("/_mesh/abc123/8080"),
+            Some(("abc123".into(), 8080, "/".into()))
+        );
+        assert_eq!(
+            parse("/_mesh/abc123/8080/"),
+            Some(("abc123".into(), 8080, "/".into()))
+        );
+        assert_eq!(parse("/_mesh//8080/api"), None);
+        assert_eq!(parse("/_mesh/abc123/http/api"), None);
+        assert_eq!(parse("/_mesh/abc123/70000/api"), None);
+        assert_eq!(parse("/_mesh/abc123"), None);
+        assert_eq!(parse("/api/_mesh/abc123/8080"), None);
+        assert_eq!(parse("/_meshy/abc123/8080"), None);
+    }
+
+    #[test]
+    fn parses_dstack_hosts() {
+        assert_eq!(
+            target(target_from_dstack_host("abc123-8080.dstack")),
+            Some(("abc123".into(), "".into(), 8080))
+        );
+        assert_eq!(
+            target(target_from_dstack_host("ABC123-443.DSTACK:80")),
+            Some(("abc123".into(), "".into(), 443))
+        );
+        assert_eq!(target(target_from_dstack_host("abc123.dstack")), None);
+        assert_eq!(target(target_from_dstack_host("abc123-80s.dstack")), None);
+        assert_eq!(target(target_from_dstack_host("abc123-80.dstack.io")), None);
+        assert_eq!(target(target_from_dstack_host("example.com")), None);
+    }
+
+    #[test]
+    fn parses_service_hosts() {
+        assert_eq!(service_from_host("db.mesh"), Some("db".into()));
+        assert_eq!(service_from_host("DB.mesh:5432"), Some("db".into()));
+        assert_eq!(service_from_host(".mesh"), None);
+        assert_eq!(service_from_host("db.mesh.local"), None);
+        assert_eq!(service_from_host("db.example.com"), None);
+    }
+
+    #[test]
+    fn parses_mesh_names() {
+        let mesh = crate::testing::TestMesh::new();
+        let config = mesh.config("[services.db]\napp_id = \"abc123\"\nport = 5432\n");
+        let runtime = ClientRuntime::new(&config).unwrap();
+        let parse = |host| target(target_from_mesh_name(host, &runtime));
+        assert_eq!(parse("db.mesh"), Some(("abc123".into(), "".into(), 5432)));
+        assert_eq!(
+            parse("ABC123-8080.mesh:80"),
+            Some(("abc123".into(), "".into(), 8080))
+        );
+        assert_eq!(parse("unknown.mesh"), None);
+        assert_eq!(parse("abc123-8080.mesh.local"), None);
+    }
+
+    #[test]
+    fn header_targets_default_to_port_443() {
+        assert_eq!(
+            target(target_from_headers(Some("abc123"), None, None)),
+            Some(("abc123".into(), "".into(), 443))
+        );
+        assert_eq!(
+            target(target_from_headers(
+                Some("abc123"),
+                Some("8080"),
+                Some("def456")
+            )),
+            Some(("abc123".into(), "def456".into(), 8080))
+        );
+        assert_eq!(target(target_from_headers(None, Some("8080"), None)), None);
+    }
+
+    #[test]
+    fn rejects_non_hex_targets() {
+        let target = |app_id: &str, instance_id: &str| TargetInfo {
+            app_id: app_id.into(),
+            instance_id: instance_id.into(),
+            port: 443,
+        };
+        assert!(validate_connection_target(&target("abc123", "")).is_ok());
+        assert!(validate_connection_target(&target("abc123", "def456")).is_ok());
+        assert!(validate_connection_target(&target("", "")).is_err());
+        assert!(validate_connection_target(&target("not-hex", "")).is_err());
+        assert!(validate_connection_target(&target("abc123", "../x")).is_err());
+        assert!(validate_connection_target(&target(&"a".repeat(65), "")).is_err());
     }
-    Ok(())
 }
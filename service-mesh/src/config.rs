@@ -1,14 +1,33 @@
+use anyhow::Context;
 use load_config::load_config;
+use rocket::data::ByteUnit;
+use rocket::figment::providers::Serialized;
 use rocket::figment::Figment;
 use serde::{Deserialize, Serialize};
-use std::net::IpAddr;
+use std::collections::{BTreeMap, HashMap};
+use std::net::{IpAddr, SocketAddr};
+use std::sync::{Arc, RwLock};
+
+use crate::policy::{PeerPolicy, PolicyRule};
 
 #[derive(Debug, Clone, Deserialize, Serialize)]
 pub struct Config {
     pub auth: AuthConfig,
     pub client: ClientConfig,
+    pub socks: SocksConfig,
+    pub inbound: InboundConfig,
     pub dstack: DstackConfig,
     pub tls: TlsConfig,
+    pub reload: ReloadConfig,
+    pub certs: CertsConfig,
+    pub telemetry: TelemetryConfig,
+    pub ready: ReadyConfig,
+    pub admin: AdminConfig,
+    #[serde(default)]
+    pub forward: Vec<ForwardConfig>,
+    /// Named aliases for mesh targets, keyed by service name
+    #[serde(default)]
+    pub services: BTreeMap<String, ServiceConfig>,
 }
 
 #[derive(Debug, Clone, Deserialize, Serialize)]
@@ -16,6 +35,17 @@ pub struct AuthConfig {
     pub enabled: bool,
     pub address: IpAddr,
     pub port: u16,
+    /// Only accept `/auth` calls over the loopback interface or `socket`. The
+    /// certificate nginx forwards is public, so `/auth` must only be reachable by it.
+    pub loopback_only: bool,
+    /// Serve `/auth` on this unix socket instead of `address`/`port`
+    #[serde(default)]
+    pub socket: Option<String>,
+    /// Peer attributes returned to backends as `x-dstack-<name>` headers
+    pub headers: Vec<String>,
+    /// Authorization rules; when empty every authenticated caller is allowed
+    #[serde(default)]
+    pub policy: Vec<PolicyRule>,
 }
 
 #[derive(Debug, Clone, Deserialize, Serialize)]
@@ -23,6 +53,184 @@ pub struct ClientConfig {
     pub enabled: bool,
     pub address: IpAddr,
     pub port: u16,
+    /// Maximum size of a request body streamed to a target
+    pub max_body_size: ByteUnit,
+    /// Per-target overrides of `max_body_size`, keyed by app_id
+    #[serde(default)]
+    pub body_limits: HashMap<String, ByteUnit>,
+    /// Measurement allowlists for upstream peers
+    #[serde(default)]
+    pub peer_policy: Vec<PeerPolicy>,
+    pub retry: RetryConfig,
+    pub circuit_breaker: CircuitBreakerConfig,
+    /// Instance sets to balance requests for an app_id across
+    #[serde(default)]
+    pub balance: Vec<BalanceConfig>,
+    pub forward_proxy: ForwardProxyConfig,
+}
+
+/// `HTTP_PROXY` compatibility: `<app_id>-<port>.dstack` hosts address mesh targets
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct ForwardProxyConfig {
+    pub enabled: bool,
+    /// Host names of the proxy itself; requests for any other non-mesh host are denied
+    pub local_hosts: Vec<String>,
+}
+
+/// Retries of failed upstream calls
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct RetryConfig {
+    /// Attempts per request including the first one; 1 disables retries
+    pub max_attempts: u32,
+    pub initial_backoff_ms: u64,
+    pub max_backoff_ms: u64,
+    /// Upstream error kinds to retry, as in `dstack_mesh_upstream_errors_total`
+    pub on_errors: Vec<String>,
+    /// Upstream response statuses to retry
+    pub on_statuses: Vec<u16>,
+    /// Methods safe to retry; other requests are only retried with an `Idempotency-Key`
+    pub methods: Vec<String>,
+    /// Larger request bodies are streamed and never retried
+    pub max_body_size: ByteUnit,
+    /// Retries allowed per request, on top of `budget_min_per_sec`
+    pub budget_ratio: f64,
+    /// Retries per second that are always allowed
+    pub budget_min_per_sec: u32,
+}
+
+impl ClientConfig {
+    /// Request body limit that applies to the given target app
+    pub fn body_limit(&self, app_id: &str) -> ByteUnit {
+        self.body_limits
+            .iter()
+            .find(|(id, _)| id.eq_ignore_ascii_case(app_id))
+            .map(|(_, limit)| *limit)
+            .unwrap_or(self.max_body_size)
+    }
+
+    /// Measurement policy that applies to the given target app, if any
+    pub fn peer_policy(&self, app_id: &str) -> Option<&PeerPolicy> {
+        self.peer_policy
+            .iter()
+            .find(|policy| policy.app_id.eq_ignore_ascii_case(app_id))
+    }
+}
+
+/// Fail fast on targets that keep failing, per `(app_id, instance, port)`
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct CircuitBreakerConfig {
+    pub enabled: bool,
+    /// Open the circuit after this many failures in a row
+    pub consecutive_failures: u32,
+    /// Open the circuit once this share of the requests in a window failed...
+    pub error_rate: f64,
+    /// ...provided the window saw at least this many requests
+    pub min_requests: u32,
+    pub window_secs: u64,
+    /// How long the circuit stays open before probing; doubles while probes fail
+    pub open_secs: u64,
+    pub max_open_secs: u64,
+    /// Concurrent calls let through to probe a half-open circuit
+    pub half_open_probes: u32,
+    /// Upstream response statuses counted as failures
+    pub failure_statuses: Vec<u16>,
+}
+
+/// Client-side load balancing across the instances of an app
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct BalanceConfig {
+    pub app_id: String,
+    #[serde(default)]
+    pub instances: Vec<String>,
+    /// File listing more instance ids, one per line, re-read when it changes
+    #[serde(default)]
+    pub instances_file: Option<String>,
+    #[serde(default)]
+    pub policy: BalancePolicy,
+    /// Header hashed by `consistent_hash`; the request path is hashed without it
+    #[serde(default)]
+    pub hash_header: Option<String>,
+    /// Requests with the same value of this header go to the same instance
+    #[serde(default)]
+    pub sticky_header: Option<String>,
+    /// Cookie set to the chosen instance so that later requests stick to it
+    #[serde(default)]
+    pub sticky_cookie: Option<String>,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum BalancePolicy {
+    #[default]
+    RoundRobin,
+    LeastOutstanding,
+    ConsistentHash,
+}
+
+/// SOCKS5 listener for tools that only speak SOCKS, resolving `.mesh` destinations
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct SocksConfig {
+    pub enabled: bool,
+    pub address: IpAddr,
+    pub port: u16,
+}
+
+/// Static L4 port forward from a local address to a mesh target
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct ForwardConfig {
+    pub listen: SocketAddr,
+    pub target_app: String,
+    pub target_port: u16,
+    #[serde(default)]
+    pub target_instance: Option<String>,
+}
+
+impl ForwardConfig {
+    pub fn target(&self) -> TargetInfo {
+        TargetInfo {
+            app_id: self.target_app.clone(),
+            instance_id: self.target_instance.clone().unwrap_or_default(),
+            port: self.target_port,
+        }
+    }
+}
+
+/// Mesh target addressed as `x-dstack-target-service: <name>` or `Host: <name>.mesh`
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct ServiceConfig {
+    pub app_id: String,
+    pub port: u16,
+    /// Whether to reach the target over mTLS; plain HTTP through the gateway otherwise
+    #[serde(default = "default_service_tls")]
+    pub tls: bool,
+    #[serde(default)]
+    pub instance: Option<String>,
+}
+
+fn default_service_tls() -> bool {
+    true
+}
+
+impl ServiceConfig {
+    pub fn target(&self) -> TargetInfo {
+        TargetInfo {
+            app_id: self.app_id.clone(),
+            instance_id: self.instance.clone().unwrap_or_default(),
+            port: self.port,
+        }
+    }
+}
+
+/// Native inbound mTLS terminator that reverse-proxies to a local backend
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct InboundConfig {
+    pub enabled: bool,
+    pub address: IpAddr,
+    pub port: u16,
+    /// Base URL of the backend service, e.g. `http://127.0.0.1:8000`. gRPC calls are
+    /// sent to it over h2c with prior knowledge
+    pub backend: String,
+    pub max_body_size: ByteUnit,
 }
 
 #[derive(Debug, Clone, Deserialize, Serialize)]
@@ -37,6 +245,58 @@ pub struct TlsConfig {
     pub ca_file: String,
 }
 
+/// Picking up changed certificates and configuration without a restart
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct ReloadConfig {
+    /// Poll the config file and `tls.*` files for changes; SIGHUP always triggers a reload
+    pub watch: bool,
+    /// Seconds between polls
+    pub interval_secs: u64,
+}
+
+/// Certificate issuance through the dstack agent
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct CertsConfig {
+    /// Re-issue the certificate from the agent before it expires
+    pub renew: bool,
+    /// Renew this many seconds before expiry (capped at a third of the certificate lifetime)
+    pub renew_before_secs: u64,
+    /// Program and arguments run after each renewal, for other processes that load the
+    /// certificate files (e.g. `["nginx", "-s", "reload"]`)
+    pub reload_command: Vec<String>,
+}
+
+/// OpenTelemetry span export
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct TelemetryConfig {
+    pub enabled: bool,
+    /// OTLP/HTTP traces endpoint of the collector
+    pub otlp_endpoint: String,
+    pub service_name: String,
+}
+
+/// Thresholds of the `/ready` checks
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct ReadyConfig {
+    /// Report not ready once the certificate expires sooner than this
+    pub cert_min_remaining_secs: i64,
+    /// Timeout of each network check
+    pub timeout_secs: u64,
+    /// Host whose TLS handshake the gateway check probes; by default the gateway name
+    /// of our own app, which exercises the same wildcard record peers dial
+    #[serde(default)]
+    pub gateway_probe_host: Option<String>,
+}
+
+/// Introspection and control API for operators
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct AdminConfig {
+    pub enabled: bool,
+    /// A loopback address, or `unix:<path>` to listen on a unix socket
+    pub address: String,
+    pub port: u16,
+}
+
 /// Target information extracted from headers
 #[derive(Debug, Clone)]
 pub struct TargetInfo {
@@ -50,3 +310,56 @@ pub const DEFAULT_CONFIG: &str = include_str!("../dstack-mesh.toml");
 pub fn load_config_figment(config_file: Option<&str>) -> Figment {
     load_config("mesh-proxy", DEFAULT_CONFIG, config_file, false)
 }
+
+/// A parsed configuration together with the figment it came from
+pub struct LoadedConfig {
+    pub figment: Figment,
+    pub config: Config,
+}
+
+impl LoadedConfig {
+    pub fn load(config_file: Option<&str>) -> anyhow::Result<Self> {
+        let figment = load_config_figment(config_file);
+        let config = figment.extract().context("Failed to load configuration")?;
+        Ok(Self { figment, config })
+    }
+}
+
+/// The configuration in effect, replaced as a whole when it is reloaded
+#[derive(Clone)]
+pub struct SharedConfig {
+    current: Arc<RwLock<Arc<LoadedConfig>>>,
+}
+
+impl SharedConfig {
+    pub fn new(loaded: LoadedConfig) -> Self {
+        Self {
+            current: Arc::new(RwLock::new(Arc::new(loaded))),
+        }
+    }
+
+    pub fn current(&self) -> Arc<LoadedConfig> {
+        self.current
+            .read()
+            .unwrap_or_else(|e| e.into_inner())
+            .clone()
+    }
+
+    pub fn replace(&self, loaded: LoadedConfig) {
+        *self.current.write().unwrap_or_else(|e| e.into_inner()) = Arc::new(loaded);
+    }
+}
+
+/// Build the Rocket figment for a service from its section of the main configuration.
+///
+/// Signals are handled by the supervisor, so Rocket's own handlers are disabled.
+pub fn rocket_figment(main_figment: &Figment, section: &str) -> anyhow::Result<Figment> {
+    let section_value = main_figment
+        .find_value(section)
+        .with_context(|| format!("{section} section not found"))?;
+    Ok(Figment::new()
+        .merge(rocket::Config::default())
+        .merge(Serialized::defaults(section_value))
+        .merge(("shutdown.ctrlc", false))
+        .merge(("shutdown.signals", Vec::<String>::new())))
+}
@@ -0,0 +1,803 @@
+use anyhow::{Context, Result};
+use bytes::Bytes;
+use http_body_util::combinators::BoxBody;
+use http_body_util::{BodyExt, Full};
+use hyper::body::{Body, Frame, Incoming, SizeHint};
+use hyper::client::conn::{http1 as client_http1, http2 as client_http2};
+use hyper::header::{HeaderMap, HeaderValue, CONTENT_TYPE, HOST, TE};
+use hyper::server::conn::http2 as server_http2;
+use hyper::service::service_fn;
+use hyper::{Request, Response, StatusCode, Uri};
+use hyper_util::rt::{TokioExecutor, TokioIo};
+use std::collections::HashMap;
+use std::convert::Infallible;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::task::{Context as TaskContext, Poll};
+use std::time::Instant;
+use tokio::io::{AsyncRead, AsyncWrite};
+use tracing::{debug, field, info_span, warn, Instrument};
+use tracing_opentelemetry::OpenTelemetrySpanExt;
+
+use crate::balance::Pick;
+use crate::breaker::{self, Permit};
+use crate::client::{
+    is_hop_by_hop, target_from_headers, target_from_mesh_name, validate_connection_target,
+    ClientRuntime, ClientState,
+};
+use crate::config::TargetInfo;
+use crate::metrics;
+use crate::stats;
+use crate::telemetry;
+
+pub(crate) type ProxyBody = BoxBody<Bytes, hyper::Error>;
+
+/// Past this many pooled connections, the least recently used one is closed
+const MAX_POOLED: usize = 1024;
+
+/// Protocols offered to targets, HTTP/2 preferred
+const UPSTREAM_ALPN: &[&[u8]] = &[b"h2", b"http/1.1"];
+
+/// gRPC status codes (see `grpc/doc/statuscodes.md`)
+const GRPC_UNKNOWN: u8 = 2;
+const GRPC_INVALID_ARGUMENT: u8 = 3;
+const GRPC_PERMISSION_DENIED: u8 = 7;
+const GRPC_RESOURCE_EXHAUSTED: u8 = 8;
+const GRPC_UNAVAILABLE: u8 = 14;
+const GRPC_UNAUTHENTICATED: u8 = 16;
+
+/// Serve h2c with prior knowledge on a client proxy connection. Unlike Rocket, it
+/// forwards request and response trailers (`grpc-status`) and streams both
+/// directions at once, which gRPC needs.
+pub(crate) async fn serve_h2c<I>(
+    io: I,
+    state: Arc<ClientState>,
+    pool: Arc<UpstreamPool>,
+) -> hyper::Result<()>
+where
+    I: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+{
+    let service = service_fn(move |req| handle_request(req, state.clone(), pool.clone()));
+    server_http2::Builder::new(TokioExecutor::new())
+        .serve_connection(TokioIo::new(io), service)
+        .await
+}
+
+/// HTTP/2 connections to targets, multiplexing all requests to the same target
+#[derive(Default)]
+pub(crate) struct UpstreamPool {
+    connections: Mutex<HashMap<String, PooledConnection>>,
+}
+
+struct PooledConnection {
+    /// Identity and policies the connection was verified with; connections opened
+    /// before a reload are not reused
+    runtime: Arc<ClientRuntime>,
+    sender: client_http2::SendRequest<ProxyBody>,
+    last_used: Instant,
+}
+
+impl UpstreamPool {
+    fn get(
+        &self,
+        key: &str,
+        runtime: &Arc<ClientRuntime>,
+    ) -> Option<client_http2::SendRequest<ProxyBody>> {
+        let mut connections = self.connections.lock().unwrap_or_else(|e| e.into_inner());
+        match connections.get_mut(key) {
+            Some(conn) if Arc::ptr_eq(&conn.runtime, runtime) && !conn.sender.is_closed() => {
+                conn.last_used = Instant::now();
+                Some(conn.sender.clone())
+            }
+            Some(_) => {
+                connections.remove(key);
+                None
+            }
+            None => None,
+        }
+    }
+
+    fn insert(
+        &self,
+        key: String,
+        runtime: Arc<ClientRuntime>,
+        sender: client_http2::SendRequest<ProxyBody>,
+    ) {
+        let mut connections = self.connections.lock().unwrap_or_else(|e| e.into_inner());
+        if connections.len() >= MAX_POOLED {
+            // Connections that closed or predate a reload are never handed out again
+            connections
+                .retain(|_, conn| Arc::ptr_eq(&conn.runtime, &runtime) && !conn.sender.is_closed());
+        }
+        if connections.len() >= MAX_POOLED {
+            let oldest = connections
+                .iter()
+                .min_by_key(|(_, conn)| conn.last_used)
+                .map(|(key, _)| key.clone());
+            if let Some(oldest) = oldest {
+                // Requests in flight keep their own sender, so only new ones are affected
+                connections.remove(&oldest);
+            }
+        }
+        connections.insert(
+            key,
+            PooledConnection {
+                runtime,
+                sender,
+                last_used: Instant::now(),
+            },
+        );
+    }
+}
+
+async fn handle_request(
+    mut req: Request<Incoming>,
+    state: Arc<ClientState>,
+    pool: Arc<UpstreamPool>,
+) -> Result<Response<ProxyBody>, Infallible> {
+    let request_id = match req
+        .headers()
+        .get("x-request-id")
+        .and_then(|v| v.to_str().ok())
+    {
+        Some(id) => id.to_string(),
+        None => {
+            let id = uuid::Uuid::new_v4().to_string();
+            if let Ok(value) = HeaderValue::from_str(&id) {
+                req.headers_mut().insert("x-request-id", value);
+            }
+            id
+        }
+    };
+    let runtime = state.runtime();
+    let grpc = is_grpc(req.headers());
+    let Some(mut target) = request_target(&req, &runtime) else {
+        return Ok(error_response(
+            grpc,
+            StatusCode::BAD_REQUEST,
+            "Missing x-dstack-target-app header or .mesh authority",
+        ));
+    };
+    if validate_connection_target(&target).is_err() {
+        return Ok(error_response(
+            grpc,
+            StatusCode::BAD_REQUEST,
+            "Invalid target",
+        ));
+    }
+    let pick = runtime.balance(&mut target, None);
+
+    let span = info_span!(
+        "proxy_request",
+        otel.kind = "client",
+        target_app = %target.app_id,
+        target_port = target.port,
+        target_instance = %target.instance_id,
+        http.method = %req.method(),
+        http.status_code = field::Empty,
+        request_id = %request_id,
+    );
+    span.set_parent(telemetry::extract_context_from(req.headers()));
+
+    let _active = stats::active(&target);
+    let started = Instant::now();
+    let method = req.method().to_string();
+    // Every request, including those on a pooled connection, needs a permit
+    let breaker_config = runtime.circuit_breaker();
+    let (status, response) = match breaker::acquire(breaker_config, &target) {
+        Err(open) => {
+            debug!("{open}");
+            metrics::record_upstream_error(&target.app_id, "circuit_open");
+            let status = StatusCode::SERVICE_UNAVAILABLE;
+            (status, error_response(grpc, status, &open.to_string()))
+        }
+        Ok(permit) => {
+            let result = send_upstream(req, &runtime, &pool, &target)
+                .instrument(span.clone())
+                .await;
+            match result {
+                Ok(response) => {
+                    let (parts, inner) = response.into_parts();
+                    let mut body = TrackedBody {
+                        inner: inner.boxed(),
+                        permit: Some(permit),
+                        _pick: pick,
+                    };
+                    if breaker_config
+                        .failure_statuses
+                        .contains(&parts.status.as_u16())
+                        || is_unavailable(&parts.headers)
+                    {
+                        body.finish(false);
+                    } else if body.inner.is_end_stream() {
+                        // Nothing left to poll, e.g. a trailers-only OK
+                        body.finish(true);
+                    }
+                    (parts.status, Response::from_parts(parts, body.boxed()))
+                }
+                Err(err) => {
+                    permit.failure();
+                    warn!("gRPC request to app_id '{}' failed: {err:?}", target.app_id);
+                    metrics::record_upstream_error(
+                        &target.app_id,
+                        metrics::upstream_error_kind(err.as_ref()),
+                    );
+                    stats::record_error(&target.app_id, target.port, format!("{err:#}"));
+                    let status = StatusCode::BAD_GATEWAY;
+                    (
+                        status,
+                        error_response(grpc, status, "Upstream request failed"),
+                    )
+                }
+            }
+        }
+    };
+    span.record("http.status_code", status.as_u16());
+    metrics::record_request(
+        &target.app_id,
+        target.port,
+        &method,
+        status.as_u16(),
+        started.elapsed(),
+    );
+    Ok(strip_hop_by_hop(response))
+}
+
+/// Whether a gRPC status in `headers` (trailers, or the headers of a trailers-only
+/// response) says the target is unavailable
+fn is_unavailable(headers: &HeaderMap) -> bool {
+    headers
+        .get("grpc-status")
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.parse::<u8>().ok())
+        == Some(GRPC_UNAVAILABLE)
+}
+
+/// Response body that reports the outcome of the call to the circuit breaker once the
+/// stream ends, and keeps the balancer's pick outstanding until then
+struct TrackedBody {
+    inner: ProxyBody,
+    permit: Option<Permit>,
+    _pick: Option<Pick>,
+}
+
+impl TrackedBody {
+    fn finish(&mut self, success: bool) {
+        if let Some(permit) = self.permit.take() {
+            if success {
+                permit.success();
+            } else {
+                permit.failure();
+            }
+        }
+    }
+}
+
+impl Body for TrackedBody {
+    type Data = Bytes;
+    type Error = hyper::Error;
+
+    fn poll_frame(
+        mut self: Pin<&mut Self>,
+        cx: &mut TaskContext<'_>,
+    ) -> Poll<Option<Result<Frame<Bytes>, hyper::Error>>> {
+        let poll = Pin::new(&mut self.inner).poll_frame(cx);
+        match &poll {
+            Poll::Ready(Some(Ok(frame))) => {
+                if let Some(trailers) = frame.trailers_ref() {
+                    let success = !is_unavailable(trailers);
+                    self.finish(success);
+                }
+            }
+            // A reset stream or a dropped connection
+            Poll::Ready(Some(Err(_))) => self.finish(false),
+            Poll::Ready(None) => self.finish(true),
+            Poll::Pending => {}
+        }
+        poll
+    }
+
+    fn is_end_stream(&self) -> bool {
+        self.inner.is_end_stream()
+    }
+
+    fn size_hint(&self) -> SizeHint {
+        self.inner.size_hint()
+    }
+}
+
+/// Send the request over a pooled HTTP/2 connection to the target, opening a new
+/// RA-TLS-verified connection if there is none. Targets that do not negotiate h2
+/// get a dedicated HTTP/1.1 connection.
+async fn send_upstream(
+    req: Request<Incoming>,
+    runtime: &Arc<ClientRuntime>,
+    pool: &UpstreamPool,
+    target: &TargetInfo,
+) -> Result<Response<Incoming>> {
+    let authority = runtime.upstream_authority(target, true);
+    let key = format!("{}/{}:{}", target.app_id, target.instance_id, target.port).to_lowercase();
+
+    if let Some(mut sender) = pool.get(&key, runtime) {
+        if sender.ready().await.is_ok() {
+            let request = upstream_request(req, &authority, true)?;
+            return Ok(sender.send_request(request).await?);
+        }
+    }
+
+    let stream = runtime.connect_upstream(target, UPSTREAM_ALPN).await?;
+    let is_h2 = stream.get_ref().1.alpn_protocol() == Some(b"h2".as_slice());
+    let io = TokioIo::new(stream);
+    if is_h2 {
+        let (mut sender, conn) = client_http2::handshake(TokioExecutor::new(), io)
+            .await
+            .context("HTTP/2 handshake failed")?;
+        let app_id = target.app_id.clone();
+        tokio::spawn(async move {
+            if let Err(e) = conn.await {
+                debug!("HTTP/2 connection to app_id '{app_id}' closed: {e}");
+            }
+        });
+        pool.insert(key, runtime.clone(), sender.clone());
+        let request = upstream_request(req, &authority, true)?;
+        Ok(sender.send_request(request).await?)
+    } else {
+        debug!(
+            "app_id '{}' did not negotiate HTTP/2, falling back to HTTP/1.1",
+            target.app_id
+        );
+        let (mut sender, conn) = client_http1::handshake(io)
+            .await
+            .context("HTTP/1.1 handshake failed")?;
+        tokio::spawn(async move {
+            if let Err(e) = conn.await {
+                debug!("HTTP/1.1 upstream connection closed: {e}");
+            }
+        });
+        let request = upstream_request(req, &authority, false)?;
+        Ok(sender.send_request(request).await?)
+    }
+}
+
+/// Rewrite a downstream request for the gateway: drop routing and hop-by-hop headers
+/// (except `te: trailers`, which gRPC servers require), propagate the trace context
+/// and address the upstream authority. The body, including its trailers, is streamed
+/// as is.
+fn upstream_request(
+    req: Request<Incoming>,
+    authority: &str,
+    http2: bool,
+) -> Result<Request<ProxyBody>> {
+    let (mut parts, body) = req.into_parts();
+    let path = parts
+        .uri
+        .path_and_query()
+        .map_or("/", |path| path.as_str())
+        .to_string();
+    let te_trailers = parts
+        .headers
+        .get_all(TE)
+        .iter()
+        .any(|value| value.to_str().is_ok_and(|v| v.contains("trailers")));
+    let names: Vec<_> = parts
+        .headers
+        .keys()
+        .filter(|name| {
+            is_hop_by_hop(name.as_str())
+                || name.as_str().starts_with("x-dstack-target-")
+                || *name == HOST
+        })
+        .cloned()
+        .collect();
+    for name in names {
+        parts.headers.remove(name);
+    }
+    if te_trailers {
+        parts
+            .headers
+            .insert(TE, HeaderValue::from_static("trailers"));
+    }
+    // Called within the request span, which becomes the upstream parent
+    telemetry::inject_headers(&mut parts.headers);
+
+    if http2 {
+        parts.uri = Uri::builder()
+            .scheme("https")
+            .authority(authority)
+            .path_and_query(path)
+            .build()
+            .context("Invalid upstream URI")?;
+        parts.version = hyper::Version::HTTP_2;
+    } else {
+        parts.uri = path.parse().context("Invalid upstream URI")?;
+        parts.version = hyper::Version::HTTP_11;
+        parts
+            .headers
+            .insert(HOST, HeaderValue::from_str(authority)?);
+    }
+    Ok(Request::from_parts(parts, body.boxed()))
+}
+
+/// Resolve the target from the `x-dstack-target-*` headers, a service alias or a
+/// `<app_id>-<port>.mesh` authority
+fn request_target(req: &Request<Incoming>, runtime: &ClientRuntime) -> Option<TargetInfo> {
+    let headers = req.headers();
+    let header = |name: &str| headers.get(name).and_then(|v| v.to_str().ok());
+    if let Some(target) = target_from_headers(
+        header("x-dstack-target-app"),
+        header("x-dstack-target-port"),
+        header("x-dstack-target-instance"),
+    ) {
+        return Some(target);
+    }
+    if let Some(name) = header("x-dstack-target-service") {
+        let target = runtime.service(name).map(|service| service.target());
+        if target.is_none() {
+            warn!("gRPC request to unknown mesh service '{name}'");
+        }
+        return target;
+    }
+    // HTTP/2 carries the authority in the URI, HTTP/1.1 in the Host header
+    let authority = req
+        .uri()
+        .authority()
+        .map(|authority| authority.as_str())
+        .or_else(|| header("host"))?;
+    target_from_mesh_name(authority, runtime)
+}
+
+fn strip_hop_by_hop(mut response: Response<ProxyBody>) -> Response<ProxyBody> {
+    let names: Vec<_> = response
+        .headers()
+        .keys()
+        .filter(|name| is_hop_by_hop(name.as_str()))
+        .cloned()
+        .collect();
+    for name in names {
+        response.headers_mut().remove(name);
+    }
+    response
+}
+
+/// Whether the request is a gRPC call, which expects errors as a `grpc-status`
+pub(crate) fn is_grpc(headers: &HeaderMap) -> bool {
+    headers
+        .get(CONTENT_TYPE)
+        .and_then(|value| value.to_str().ok())
+        .is_some_and(|value| value.starts_with("application/grpc"))
+}
+
+/// gRPC status code for an error the proxy produced itself with HTTP `status`
+fn grpc_code(status: StatusCode) -> u8 {
+    match status {
+        // The request could not be routed to a target
+        StatusCode::BAD_REQUEST | StatusCode::NOT_FOUND => GRPC_INVALID_ARGUMENT,
+        StatusCode::UNAUTHORIZED => GRPC_UNAUTHENTICATED,
+        StatusCode::FORBIDDEN => GRPC_PERMISSION_DENIED,
+        StatusCode::PAYLOAD_TOO_LARGE => GRPC_RESOURCE_EXHAUSTED,
+        StatusCode::BAD_GATEWAY | StatusCode::SERVICE_UNAVAILABLE | StatusCode::GATEWAY_TIMEOUT => {
+            GRPC_UNAVAILABLE
+        }
+        _ => GRPC_UNKNOWN,
+    }
+}
+
+/// Error response for a request the proxy could not forward.
+///
+/// gRPC callers get a trailers-only response: HTTP 200 with `grpc-status` and
+/// `grpc-message` in the headers, as gRPC clients ignore the HTTP status. Other
+/// callers get `status` with a JSON body.
+pub(crate) fn error_response(grpc: bool, status: StatusCode, message: &str) -> Response<ProxyBody> {
+    if grpc {
+        let mut response = Response::new(full(Bytes::new()));
+        let headers = response.headers_mut();
+        headers.insert(CONTENT_TYPE, HeaderValue::from_static("application/grpc"));
+        headers.insert("grpc-status", HeaderValue::from(grpc_code(status)));
+        if let Ok(message) = HeaderValue::from_str(&urlencoding::encode(message)) {
+            headers.insert("grpc-message", message);
+        }
+        return response;
+    }
+    let body = serde_json::json!({ "error": message }).to_string();
+    let mut response = Response::new(full(Bytes::from(body)));
+    *response.status_mut() = status;
+    response
+        .headers_mut()
+        .insert(CONTENT_TYPE, HeaderValue::from_static("application/json"));
+    response
+}
+
+fn full(bytes: Bytes) -> ProxyBody {
+    Full::new(bytes).map_err(|never| match never {}).boxed()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::inbound::run_inbound_proxy;
+    use crate::server::AuthState;
+    use crate::testing::{free_port, wait_for_port, TestMesh, APP_ID};
+    use futures_util::stream::BoxStream;
+    use futures_util::StreamExt;
+    use hyper::http::uri::PathAndQuery;
+    use opentelemetry::global;
+    use opentelemetry::trace::TracerProvider as _;
+    use opentelemetry_sdk::propagation::TraceContextPropagator;
+    use opentelemetry_sdk::trace::SdkTracerProvider;
+    use std::future::{ready, Ready};
+    use tokio::net::TcpListener;
+    use tokio_util::sync::CancellationToken;
+    use tonic::codec::{ProstCodec, Streaming};
+    use tonic::transport::Endpoint;
+    use tonic::Code;
+    use tracing_subscriber::layer::SubscriberExt;
+
+    fn grpc_headers(response: &Response<ProxyBody>) -> (Option<&str>, Option<&str>) {
+        let header = |name| response.headers().get(name).and_then(|v| v.to_str().ok());
+        (header("grpc-status"), header("grpc-message"))
+    }
+
+    #[test]
+    fn detects_grpc_requests() {
+        let mut headers = HeaderMap::new();
+        assert!(!is_grpc(&headers));
+        headers.insert(
+            CONTENT_TYPE,
+            HeaderValue::from_static("application/grpc+proto"),
+        );
+        assert!(is_grpc(&headers));
+        headers.insert(CONTENT_TYPE, HeaderValue::from_static("application/json"));
+        assert!(!is_grpc(&headers));
+    }
+
+    #[test]
+    fn grpc_errors_are_trailers_only() {
+        let response = error_response(true, StatusCode::BAD_GATEWAY, "Upstream request failed");
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(
+            grpc_headers(&response),
+            (Some("14"), Some("Upstream%20request%20failed"))
+        );
+
+        let response = error_response(true, StatusCode::BAD_REQUEST, "Invalid target");
+        assert_eq!(grpc_headers(&response).0, Some("3"));
+    }
+
+    #[test]
+    fn plain_errors_keep_http_status() {
+        let response = error_response(false, StatusCode::BAD_GATEWAY, "Upstream request failed");
+        assert_eq!(response.status(), StatusCode::BAD_GATEWAY);
+        assert_eq!(grpc_headers(&response), (None, None));
+    }
+
+    /// Request of the test echo service: `count` replies, then `code` as the status
+    #[derive(Clone, PartialEq, prost::Message)]
+    struct EchoRequest {
+        #[prost(string, tag = "1")]
+        message: String,
+        #[prost(uint32, tag = "2")]
+        count: u32,
+        #[prost(int32, tag = "3")]
+        code: i32,
+    }
+
+    /// A reply, with the request headers the backend saw for tracing
+    #[derive(Clone, PartialEq, prost::Message)]
+    struct EchoReply {
+        #[prost(string, tag = "1")]
+        message: String,
+        #[prost(string, tag = "2")]
+        request_id: String,
+        #[prost(string, tag = "3")]
+        traceparent: String,
+    }
+
+    struct Echo;
+
+    impl tonic::server::ServerStreamingService<EchoRequest> for Echo {
+        type Response = EchoReply;
+        type ResponseStream = BoxStream<'static, Result<EchoReply, tonic::Status>>;
+        type Future = Ready<Result<tonic::Response<Self::ResponseStream>, tonic::Status>>;
+
+        fn call(&mut self, request: tonic::Request<EchoRequest>) -> Self::Future {
+            let header = |name| {
+                let value = request.metadata().get(name);
+                value
+                    .and_then(|v| v.to_str().ok())
+                    .unwrap_or_default()
+                    .to_string()
+            };
+            let (request_id, traceparent) = (header("x-request-id"), header("traceparent"));
+            let EchoRequest {
+                message,
+                count,
+                code,
+            } = request.into_inner();
+            let replies = (0..count).map(move |i| {
+                Ok(EchoReply {
+                    message: format!("{message} #{i}"),
+                    request_id: request_id.clone(),
+                    traceparent: traceparent.clone(),
+                })
+            });
+            let end = (code != 0).then(|| Err(tonic::Status::new(code.into(), "echo ended")));
+            ready(Ok(tonic::Response::new(
+                futures_util::stream::iter(replies.chain(end)).boxed(),
+            )))
+        }
+    }
+
+    /// The backend: a tonic echo server speaking h2c
+    async fn serve_echo(listener: TcpListener) {
+        while let Ok((stream, _)) = listener.accept().await {
+            tokio::spawn(async move {
+                let service = service_fn(|req: Request<Incoming>| async move {
+                    let mut grpc = tonic::server::Grpc::new(ProstCodec::default());
+                    Ok::<_, Infallible>(grpc.server_streaming(Echo, req).await)
+                });
+                let _ = server_http2::Builder::new(TokioExecutor::new())
+                    .serve_connection(TokioIo::new(stream), service)
+                    .await;
+            });
+        }
+    }
+
+    /// The client side: the grpc path of the client proxy listener
+    async fn serve_proxy(listener: TcpListener, state: Arc<ClientState>) {
+        let pool = Arc::new(UpstreamPool::default());
+        while let Ok((stream, _)) = listener.accept().await {
+            tokio::spawn(serve_h2c(stream, state.clone(), pool.clone()));
+        }
+    }
+
+    async fn call_echo(
+        proxy_port: u16,
+        app_id: &str,
+        request: EchoRequest,
+        headers: &[(&'static str, &str)],
+    ) -> Result<Streaming<EchoReply>, tonic::Status> {
+        let channel = Endpoint::from_shared(format!("http://127.0.0.1:{proxy_port}"))
+            .unwrap()
+            .connect()
+            .await
+            .unwrap();
+        let mut client = tonic::client::Grpc::new(channel);
+        client.ready().await.unwrap();
+        let mut request = tonic::Request::new(request);
+        let metadata = request.metadata_mut();
+        metadata.insert("x-dstack-target-app", app_id.parse().unwrap());
+        metadata.insert("x-dstack-target-port", "50051".parse().unwrap());
+        for (name, value) in headers {
+            metadata.insert(*name, value.parse().unwrap());
+        }
+        let path = PathAndQuery::from_static("/test.Echo/Echo");
+        let response = client
+            .server_streaming(request, path, ProstCodec::default())
+            .await?;
+        Ok(response.into_inner())
+    }
+
+    /// Start the echo backend behind an inbound proxy, which serves as the RA-TLS
+    /// terminator, and a client proxy in front. Returns the client proxy port.
+    async fn start_mesh(mesh: &TestMesh, shutdown: &CancellationToken) -> u16 {
+        let echo = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let backend_port = echo.local_addr().unwrap().port();
+        tokio::spawn(serve_echo(echo));
+
+        // The RA-TLS terminator is the inbound proxy, which the gateway address points at
+        let inbound_port = free_port();
+        let config = mesh.config(&format!(
+            r#"
+[dstack]
+gateway_domain = "fixed/127.0.0.1:{inbound_port}"
+
+[inbound]
+enabled = true
+address = "127.0.0.1"
+port = {inbound_port}
+backend = "http://127.0.0.1:{backend_port}"
+"#
+        ));
+        let auth = Arc::new(AuthState::new(&config).unwrap());
+        tokio::spawn({
+            let config = config.clone();
+            let shutdown = shutdown.clone();
+            async move {
+                run_inbound_proxy(&config, auth, Arc::default(), shutdown)
+                    .await
+                    .unwrap()
+            }
+        });
+        wait_for_port(inbound_port).await;
+
+        let proxy = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let proxy_port = proxy.local_addr().unwrap().port();
+        let state = Arc::new(ClientState::new(&config).unwrap());
+        tokio::spawn(serve_proxy(proxy, state));
+        proxy_port
+    }
+
+    #[tokio::test]
+    async fn proxies_grpc_streams_and_status_end_to_end() {
+        let mesh = TestMesh::new();
+        let shutdown = CancellationToken::new();
+        let proxy_port = start_mesh(&mesh, &shutdown).await;
+
+        // Replies stream through and the call ends with the OK status from the trailers
+        let request = EchoRequest {
+            message: "hello".to_string(),
+            count: 3,
+            code: 0,
+        };
+        let mut replies = call_echo(proxy_port, APP_ID, request, &[]).await.unwrap();
+        for i in 0..3 {
+            let reply = replies.message().await.unwrap().unwrap();
+            assert_eq!(reply.message, format!("hello #{i}"));
+        }
+        assert!(replies.message().await.unwrap().is_none());
+
+        // A status the backend sends after some replies reaches the caller
+        let request = EchoRequest {
+            message: "hello".to_string(),
+            count: 2,
+            code: Code::NotFound as i32,
+        };
+        let mut replies = call_echo(proxy_port, APP_ID, request, &[]).await.unwrap();
+        for _ in 0..2 {
+            replies.message().await.unwrap().unwrap();
+        }
+        let status = replies.message().await.unwrap_err();
+        assert_eq!(status.code(), Code::NotFound);
+        assert_eq!(status.message(), "echo ended");
+
+        // Requests the proxy cannot route get a trailers-only status
+        let status = call_echo(proxy_port, "not-an-app-id", EchoRequest::default(), &[])
+            .await
+            .unwrap_err();
+        assert_eq!(status.code(), Code::InvalidArgument);
+        assert_eq!(status.message(), "Invalid target");
+
+        shutdown.cancel();
+    }
+
+    #[tokio::test]
+    async fn propagates_request_id_and_trace_context() {
+        // Record spans like the OTLP exporter would, without sending them anywhere
+        let provider = SdkTracerProvider::builder().build();
+        let subscriber = tracing_subscriber::registry()
+            .with(tracing_opentelemetry::layer().with_tracer(provider.tracer("test")));
+        let _subscriber = tracing::subscriber::set_default(subscriber);
+        global::set_text_map_propagator(TraceContextPropagator::new());
+
+        let mesh = TestMesh::new();
+        let shutdown = CancellationToken::new();
+        let proxy_port = start_mesh(&mesh, &shutdown).await;
+        let request = || EchoRequest {
+            message: "hello".to_string(),
+            count: 1,
+            code: 0,
+        };
+
+        // The caller's trace continues with the proxies' spans as parents
+        let trace_id = "0af7651916cd43dd8448eb211c80319c";
+        let traceparent = format!("00-{trace_id}-b7ad6b7169203331-01");
+        let headers = [
+            ("traceparent", traceparent.as_str()),
+            ("x-request-id", "req-1"),
+        ];
+        let mut replies = call_echo(proxy_port, APP_ID, request(), &headers)
+            .await
+            .unwrap();
+        let reply = replies.message().await.unwrap().unwrap();
+        assert_eq!(reply.request_id, "req-1");
+        assert!(reply.traceparent.starts_with(&format!("00-{trace_id}-")));
+        assert_ne!(reply.traceparent, traceparent);
+
+        // Requests without an id get one
+        let mut replies = call_echo(proxy_port, APP_ID, request(), &[]).await.unwrap();
+        let reply = replies.message().await.unwrap().unwrap();
+        assert!(uuid::Uuid::parse_str(&reply.request_id).is_ok());
+
+        shutdown.cancel();
+    }
+}
@@ -0,0 +1,109 @@
+use anyhow::{bail, Context, Result};
+use ra_tls::attestation::Attestation;
+use ra_tls::traits::CertExt as _;
+use serde::Serialize;
+use sha2::{Digest, Sha256};
+use tracing::warn;
+
+/// Peer attributes that can be exposed to backends, as `x-dstack-<name>` headers
+pub const ATTRIBUTES: &[&str] = &[
+    "app-id",
+    "instance-id",
+    "compose-hash",
+    "os-image-hash",
+    "device-id",
+    "cert-fingerprint",
+    "cert-not-after",
+];
+
+/// Identity of a mesh peer as carried by its RA-TLS certificate
+#[derive(Debug, Clone, Serialize)]
+pub struct PeerIdentity {
+    pub app_id: String,
+    pub instance_id: Option<String>,
+    pub compose_hash: Option<String>,
+    pub os_image_hash: Option<String>,
+    pub device_id: Option<String>,
+    /// Hex-encoded SHA-256 of the DER certificate
+    pub fingerprint: String,
+    /// Certificate expiry as a unix timestamp
+    pub not_after: i64,
+}
+
+impl PeerIdentity {
+    /// Parse the RA-TLS extensions of a DER-encoded certificate
+    pub fn from_der(der: &[u8]) -> Result<Self> {
+        let (_, cert) =
+            x509_parser::parse_x509_certificate(der).context("Failed to parse certificate")?;
+        let Some(app_id) = cert
+            .get_app_id()
+            .context("Failed to get app_id from cert")?
+        else {
+            bail!("No app_id found in cert");
+        };
+
+        let mut identity = Self {
+            app_id: hex::encode(app_id),
+            instance_id: None,
+            compose_hash: None,
+            os_image_hash: None,
+            device_id: None,
+            fingerprint: hex::encode(Sha256::digest(der)),
+            not_after: cert.validity().not_after.timestamp(),
+        };
+
+        // The remaining attributes come from the embedded attestation, when present
+        match Attestation::from_cert(&cert)
+            .and_then(|attestation| attestation.map(|a| a.decode_app_info(false)).transpose())
+        {
+            Ok(Some(info)) => {
+                identity.instance_id = non_empty_hex(&info.instance_id);
+                identity.compose_hash = non_empty_hex(&info.compose_hash);
+                identity.os_image_hash = non_empty_hex(&info.os_image_hash);
+                identity.device_id = non_empty_hex(&info.device_id);
+            }
+            Ok(None) => {}
+            Err(err) => warn!("Failed to decode attestation from cert: {err:?}"),
+        }
+        Ok(identity)
+    }
+
+    /// Value of the named attribute (see [`ATTRIBUTES`]), if the peer has it
+    pub fn attribute(&self, name: &str) -> Option<String> {
+        match name {
+            "app-id" => Some(self.app_id.clone()),
+            "instance-id" => self.instance_id.clone(),
+            "compose-hash" => self.compose_hash.clone(),
+            "os-image-hash" => self.os_image_hash.clone(),
+            "device-id" => self.device_id.clone(),
+            "cert-fingerprint" => Some(self.fingerprint.clone()),
+            "cert-not-after" => Some(self.not_after.to_string()),
+            _ => None,
+        }
+    }
+
+    /// `(header, value)` pairs for the selected attributes the peer has
+    pub fn headers(&self, attributes: &[String]) -> Vec<(String, String)> {
+        attributes
+            .iter()
+            .filter_map(|name| {
+                self.attribute(name)
+                    .map(|value| (format!("x-dstack-{name}"), value))
+            })
+            .collect()
+    }
+}
+
+/// Ensure every configured attribute name is one we know how to emit
+pub fn validate_attributes(attributes: &[String]) -> Result<()> {
+    for name in attributes {
+        if !ATTRIBUTES.contains(&name.as_str()) {
+            bail!("Unknown peer attribute '{name}', expected one of {ATTRIBUTES:?}");
+        }
+    }
+    Ok(())
+}
+
+fn non_empty_hex(bytes: &[u8]) -> Option<String> {
+    (!bytes.is_empty()).then(|| hex::encode(bytes))
+}
@@ -0,0 +1,392 @@
+use anyhow::{bail, Context, Result};
+use bytes::Bytes;
+use http_body_util::combinators::BoxBody;
+use http_body_util::{BodyExt, LengthLimitError, Limited};
+use hyper::body::Incoming;
+use hyper::header::{HeaderMap, HeaderName, HeaderValue, CONNECTION, CONTENT_LENGTH, TE, UPGRADE};
+use hyper::service::service_fn;
+use hyper::{Request, Response, StatusCode, Uri, Version};
+use hyper_util::client::legacy::connect::HttpConnector;
+use hyper_util::client::legacy::Client;
+use hyper_util::rt::{TokioExecutor, TokioIo};
+use hyper_util::server::conn::auto;
+use rocket::data::ByteUnit;
+use rustls::ServerConfig;
+use std::convert::Infallible;
+use std::error::Error as StdError;
+use std::net::SocketAddr;
+use std::sync::{Arc, RwLock};
+use std::time::Duration;
+use tokio::net::{TcpListener, TcpStream};
+use tokio_rustls::TlsAcceptor;
+use tokio_util::sync::CancellationToken;
+use tokio_util::task::TaskTracker;
+use tracing::{debug, field, info, info_span, warn, Instrument, Span};
+use tracing_opentelemetry::OpenTelemetrySpanExt;
+
+use crate::client::is_hop_by_hop;
+use crate::config::Config;
+use crate::grpc::{error_response, is_grpc, ProxyBody};
+use crate::identity::PeerIdentity;
+use crate::server::{authorize, AuthState};
+use crate::supervisor::drain_connections;
+use crate::telemetry;
+use crate::tls::{CertResolver, TlsMaterial};
+
+/// Protocols offered to callers, HTTP/2 preferred; gRPC needs it
+const ALPN_PROTOCOLS: &[&[u8]] = &[b"h2", b"http/1.1"];
+/// How long a caller may take to complete the TLS handshake
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+type BackendBody = BoxBody<Bytes, Box<dyn StdError + Send + Sync>>;
+type BackendClient = Client<HttpConnector, BackendBody>;
+
+/// TLS settings of the inbound listener, replaced on reload without restarting it.
+///
+/// The certificate is served through a resolver; the config around it is rebuilt as
+/// well, since the CA that callers are verified against may have changed. Connections
+/// already open keep the session they negotiated.
+#[derive(Default)]
+pub struct InboundTls {
+    resolver: Arc<CertResolver>,
+    config: RwLock<Option<Arc<ServerConfig>>>,
+}
+
+impl InboundTls {
+    /// Use `material` for new handshakes. Nothing changes if it fails.
+    pub fn replace(&self, material: &TlsMaterial) -> Result<()> {
+        let key = material.certified_key()?;
+        let config = material.server_config(self.resolver.clone(), ALPN_PROTOCOLS)?;
+        self.resolver.replace(key);
+        *self.config.write().unwrap_or_else(|e| e.into_inner()) = Some(Arc::new(config));
+        Ok(())
+    }
+
+    fn acceptor(&self) -> Option<TlsAcceptor> {
+        let config = self.config.read().unwrap_or_else(|e| e.into_inner());
+        config.clone().map(TlsAcceptor::from)
+    }
+}
+
+struct InboundState {
+    /// Base URL of the backend, without a trailing slash
+    backend: String,
+    max_body_size: ByteUnit,
+    auth: Arc<AuthState>,
+    /// HTTP/1.1 client for regular requests and upgrades
+    http1: BackendClient,
+    /// h2c client with prior knowledge for gRPC, which needs HTTP/2 end to end
+    http2: BackendClient,
+}
+
+/// Run the native inbound listener.
+///
+/// It terminates mTLS with our own certificate, requires client certificates chained
+/// to the mesh CA and reverse-proxies to the backend with `X-Dstack-App-Id` set.
+/// Callers may speak HTTP/1.1 or HTTP/2; gRPC calls are forwarded over h2c with
+/// their trailers, so `grpc-status` reaches the caller.
+pub async fn run_inbound_proxy(
+    config: &Config,
+    auth: Arc<AuthState>,
+    tls: Arc<InboundTls>,
+    shutdown: CancellationToken,
+) -> Result<()> {
+    let backend = config.inbound.backend.trim_end_matches('/').to_string();
+    let uri: Uri = backend.parse().context("Invalid inbound backend URL")?;
+    if uri.scheme_str() != Some("http") {
+        bail!("Inbound backend must be an http:// URL, got '{backend}'");
+    }
+    let material = TlsMaterial::load(&config.tls).context("Failed to load TLS material")?;
+    tls.replace(&material)?;
+
+    let state = Arc::new(InboundState {
+        backend,
+        max_body_size: config.inbound.max_body_size,
+        auth,
+        http1: Client::builder(TokioExecutor::new()).build_http(),
+        http2: Client::builder(TokioExecutor::new())
+            .http2_only(true)
+            .build_http(),
+    });
+
+    let addr = SocketAddr::new(config.inbound.address, config.inbound.port);
+    let listener = TcpListener::bind(addr)
+        .await
+        .with_context(|| format!("Failed to bind inbound proxy on {addr}"))?;
+    info!(
+        "Inbound proxy listening on {addr}, forwarding to {}",
+        state.backend
+    );
+
+    let tracker = TaskTracker::new();
+    loop {
+        let (stream, peer) = tokio::select! {
+            accepted = listener.accept() => accepted.context("Failed to accept inbound connection")?,
+            _ = shutdown.cancelled() => break,
+        };
+        let Some(acceptor) = tls.acceptor() else {
+            continue;
+        };
+        let state = state.clone();
+        let shutdown = shutdown.clone();
+        tracker.spawn(async move {
+            if let Err(e) = serve(stream, acceptor, state, shutdown).await {
+                debug!("Inbound connection from {peer} failed: {e:#}");
+            }
+        });
+    }
+    drain_connections(tracker, "inbound").await;
+    Ok(())
+}
+
+async fn serve(
+    stream: TcpStream,
+    acceptor: TlsAcceptor,
+    state: Arc<InboundState>,
+    shutdown: CancellationToken,
+) -> Result<()> {
+    let stream = tokio::time::timeout(HANDSHAKE_TIMEOUT, acceptor.accept(stream))
+        .await
+        .context("Timed out during the TLS handshake")?
+        .context("TLS handshake failed")?;
+
+    // rustls has already chain-validated the client certificate against the CA
+    let peer = stream
+        .get_ref()
+        .1
+        .peer_certificates()
+        .and_then(|certs| certs.first())
+        .context("No client certificate")
+        .and_then(|cert| PeerIdentity::from_der(cert));
+    let peer = match peer {
+        Ok(peer) => Some(Arc::new(peer)),
+        Err(e) => {
+            // Answered with 401 per request, like a missing identity header
+            warn!("Inbound auth failed: {e:?}");
+            None
+        }
+    };
+
+    let service = service_fn(move |req| handle_request(req, state.clone(), peer.clone()));
+    let builder = auto::Builder::new(TokioExecutor::new());
+    let conn = builder.serve_connection_with_upgrades(TokioIo::new(stream), service);
+    tokio::pin!(conn);
+    let result = tokio::select! {
+        result = conn.as_mut() => result,
+        _ = shutdown.cancelled() => {
+            conn.as_mut().graceful_shutdown();
+            conn.await
+        }
+    };
+    result.map_err(|e| anyhow::anyhow!("{e}"))
+}
+
+async fn handle_request(
+    mut req: Request<Incoming>,
+    state: Arc<InboundState>,
+    peer: Option<Arc<PeerIdentity>>,
+) -> Result<Response<ProxyBody>, Infallible> {
+    let request_id = match req
+        .headers()
+        .get("x-request-id")
+        .and_then(|v| v.to_str().ok())
+    {
+        Some(id) => id.to_string(),
+        None => {
+            let id = uuid::Uuid::new_v4().to_string();
+            if let Ok(value) = HeaderValue::from_str(&id) {
+                req.headers_mut().insert("x-request-id", value);
+            }
+            id
+        }
+    };
+    let span = info_span!(
+        "inbound_request",
+        otel.kind = "server",
+        http.method = %req.method(),
+        http.status_code = field::Empty,
+        caller_app = field::Empty,
+        decision = field::Empty,
+        request_id = %request_id,
+    );
+    span.set_parent(telemetry::extract_context_from(req.headers()));
+
+    let grpc = is_grpc(req.headers());
+    let result = proxy_to_backend(req, &state, peer.as_deref())
+        .instrument(span.clone())
+        .await;
+    let (status, response) = match result {
+        Ok(response) => (response.status(), response),
+        Err(status) => (
+            status,
+            error_response(grpc, status, status.canonical_reason().unwrap_or("Error")),
+        ),
+    };
+    span.record("http.status_code", status.as_u16());
+    Ok(response)
+}
+
+async fn proxy_to_backend(
+    mut req: Request<Incoming>,
+    state: &InboundState,
+    peer: Option<&PeerIdentity>,
+) -> Result<Response<ProxyBody>, StatusCode> {
+    let Some(peer) = peer else {
+        return Err(StatusCode::UNAUTHORIZED);
+    };
+    Span::current().record("caller_app", peer.app_id.as_str());
+
+    let auth = state.auth.settings();
+    authorize(
+        &auth.policy,
+        &peer.app_id,
+        req.method().as_str(),
+        req.uri().path(),
+    )
+    .map_err(|_| StatusCode::FORBIDDEN)?;
+
+    let limit = state.max_body_size.as_u64();
+    let declared_length = req
+        .headers()
+        .get(CONTENT_LENGTH)
+        .and_then(|v| v.to_str().ok()?.parse::<u64>().ok());
+    if declared_length.is_some_and(|length| length > limit) {
+        warn!("Request body exceeds limit of {}", state.max_body_size);
+        return Err(StatusCode::PAYLOAD_TOO_LARGE);
+    }
+
+    // Upgrades (e.g. WebSocket handshakes) only exist in HTTP/1.1
+    let upgrade = req
+        .headers()
+        .get(UPGRADE)
+        .filter(|_| wants_upgrade(req.headers()))
+        .cloned();
+    let on_upgrade = upgrade.as_ref().map(|_| hyper::upgrade::on(&mut req));
+    let grpc = req.version() == Version::HTTP_2 && is_grpc(req.headers());
+
+    let (mut parts, body) = req.into_parts();
+    let path = parts.uri.path_and_query().map_or("/", |path| path.as_str());
+    let url = format!("{}{path}", state.backend);
+    parts.uri = url.parse().map_err(|_| StatusCode::BAD_REQUEST)?;
+    parts.version = if grpc {
+        Version::HTTP_2
+    } else {
+        Version::HTTP_11
+    };
+    forward_headers(&mut parts.headers, peer, &auth.headers, upgrade);
+
+    debug!(
+        "Forwarding inbound request from app_id '{}' to {url}",
+        peer.app_id
+    );
+
+    let body = Limited::new(body, usize::try_from(limit).unwrap_or(usize::MAX)).boxed();
+    let client = if grpc { &state.http2 } else { &state.http1 };
+    let mut response = match client.request(Request::from_parts(parts, body)).await {
+        Ok(response) => response,
+        Err(e) if exceeded_limit(&e) => {
+            warn!("Request body exceeds limit of {}", state.max_body_size);
+            return Err(StatusCode::PAYLOAD_TOO_LARGE);
+        }
+        Err(e) => {
+            tracing::error!("Backend request failed: {e}");
+            return Err(StatusCode::BAD_GATEWAY);
+        }
+    };
+
+    if let Some(on_upgrade) = on_upgrade {
+        if response.status() == StatusCode::SWITCHING_PROTOCOLS {
+            debug!("Backend accepted the upgrade");
+            let backend_upgrade = hyper::upgrade::on(&mut response);
+            tokio::spawn(async move {
+                match tokio::try_join!(on_upgrade, backend_upgrade) {
+                    Ok((caller, backend)) => {
+                        let _ = tokio::io::copy_bidirectional(
+                            &mut TokioIo::new(caller),
+                            &mut TokioIo::new(backend),
+                        )
+                        .await;
+                    }
+                    Err(e) => debug!("Inbound upgrade failed: {e}"),
+                }
+            });
+            // The 101 keeps its `connection`/`upgrade` headers
+            return Ok(response.map(|body| body.boxed()));
+        }
+    }
+
+    let names: Vec<_> = response
+        .headers()
+        .keys()
+        .filter(|name| is_hop_by_hop(name.as_str()))
+        .cloned()
+        .collect();
+    for name in names {
+        response.headers_mut().remove(name);
+    }
+    // Streamed as is, including trailers such as `grpc-status`
+    Ok(response.map(|body| body.boxed()))
+}
+
+/// Prepare the caller's headers for the backend: drop hop-by-hop headers (except
+/// `te: trailers`, which gRPC servers require) and identity headers supplied by the
+/// caller, which are never trusted, then add the verified identity
+fn forward_headers(
+    headers: &mut HeaderMap,
+    peer: &PeerIdentity,
+    attributes: &[String],
+    upgrade: Option<HeaderValue>,
+) {
+    let te_trailers = headers
+        .get_all(TE)
+        .iter()
+        .any(|value| value.to_str().is_ok_and(|v| v.contains("trailers")));
+    let names: Vec<_> = headers
+        .keys()
+        .filter(|name| {
+            is_hop_by_hop(name.as_str())
+                || name.as_str().starts_with("x-dstack-")
+                || name.as_str() == "x-forwarded-proto"
+        })
+        .cloned()
+        .collect();
+    for name in names {
+        headers.remove(name);
+    }
+    if te_trailers {
+        headers.insert(TE, HeaderValue::from_static("trailers"));
+    }
+    if let Some(protocol) = upgrade {
+        headers.insert(CONNECTION, HeaderValue::from_static("upgrade"));
+        headers.insert(UPGRADE, protocol);
+    }
+    headers.insert("x-forwarded-proto", HeaderValue::from_static("https"));
+    telemetry::inject_headers(headers);
+    for (name, value) in peer.headers(attributes) {
+        if let (Ok(name), Ok(value)) = (HeaderName::try_from(name), HeaderValue::try_from(value)) {
+            headers.append(name, value);
+        }
+    }
+}
+
+fn wants_upgrade(headers: &HeaderMap) -> bool {
+    headers.get_all(CONNECTION).iter().any(|value| {
+        value.to_str().is_ok_and(|value| {
+            value
+                .split(',')
+                .any(|token| token.trim().eq_ignore_ascii_case("upgrade"))
+        })
+    })
+}
+
+/// Whether sending failed because the request body outgrew the limit
+fn exceeded_limit(err: &(dyn StdError + 'static)) -> bool {
+    let mut source = Some(err);
+    while let Some(err) = source {
+        if err.is::<LengthLimitError>() {
+            return true;
+        }
+        source = err.source();
+    }
+    false
+}
@@ -1,12 +1,40 @@
-use anyhow::{Context, Result};
-use clap::Parser;
-use config::{load_config_figment, Config};
-use tracing::info;
-use tracing_subscriber::EnvFilter;
+use anyhow::{bail, Context, Result};
+use clap::{Parser, Subcommand};
+use client::ClientState;
+use config::{LoadedConfig, SharedConfig};
+use inbound::InboundTls;
+use ready::Readiness;
+use reload::Reloader;
+use server::AuthState;
+use std::sync::Arc;
+use supervisor::Supervisor;
+use tracing::{info, warn};
 
+mod admin;
+mod agent;
+mod balance;
+mod breaker;
+mod certs;
 mod client;
 mod config;
+mod grpc;
+mod identity;
+mod inbound;
+mod metrics;
+mod mux;
+mod policy;
+mod ready;
+mod reload;
+mod retry;
 mod server;
+mod socks;
+mod stats;
+mod supervisor;
+mod telemetry;
+#[cfg(test)]
+mod testing;
+mod tls;
+mod tunnel;
 
 fn app_version() -> String {
     const CARGO_PKG_VERSION: &str = env!("CARGO_PKG_VERSION");
@@ -24,41 +52,213 @@ fn app_version() -> String {
 #[command(version)]
 struct Args {
     /// Path to the configuration file
-    #[arg(short, long)]
+    #[arg(short, long, global = true)]
     config: Option<String>,
+    #[command(subcommand)]
+    command: Option<Command>,
 }
 
-#[rocket::main]
-async fn main() -> Result<()> {
-    // Initialize tracing with JSON output for Datadog
-    tracing_subscriber::fmt()
-        .json()
-        .with_env_filter(
-            EnvFilter::try_from_default_env().unwrap_or_else(|_| EnvFilter::new("info")),
-        )
-        .init();
+#[derive(Subcommand)]
+enum Command {
+    /// Manage the mesh mTLS certificates
+    Certs {
+        #[command(subcommand)]
+        command: CertsCommand,
+    },
+}
+
+#[derive(Subcommand)]
+enum CertsCommand {
+    /// Fetch a certificate from the dstack agent and write it to the `tls.*` files
+    Init,
+}
 
+fn main() -> Result<()> {
     let args = Args::parse();
 
     // Load configuration
-    let figment = load_config_figment(args.config.as_deref());
-    let config: Config = figment.extract().context("Failed to load configuration")?;
+    let loaded = LoadedConfig::load(args.config.as_deref())?;
+
+    // Set up logging and tracing before the async runtime starts: the OTLP exporter
+    // uses a blocking HTTP client that must not live inside it
+    let tracer_provider = telemetry::init(&loaded.config.telemetry)?;
+    metrics::init();
+
+    let result = rocket::execute(run(args, loaded));
+
+    if let Some(provider) = tracer_provider {
+        if let Err(err) = provider.shutdown() {
+            warn!("Failed to flush trace spans: {err}");
+        }
+    }
+    result
+}
+
+async fn run(args: Args, loaded: LoadedConfig) -> Result<()> {
+    if let Some(Command::Certs {
+        command: CertsCommand::Init,
+    }) = args.command
+    {
+        let tls = &loaded.config.tls;
+        certs::issue(tls)
+            .await
+            .context("Failed to issue certificate")?;
+        info!("Certificate written to {}", tls.cert_file);
+        return Ok(());
+    }
 
     info!("Starting dstack mesh proxy {}", app_version());
     info!("Configuration loaded successfully");
 
-    // Start both services - each service creates its own Rocket figment internally
-    tokio::select! {
-        result = client::run_client_proxy(
-            &figment,
-            &config
-        ) => {
-            result.context("Client proxy failed")?;
+    // Services read the configuration when they (re)start, so reloads apply to them
+    let config = loaded.config.clone();
+    let shared = SharedConfig::new(loaded);
+    let mut supervisor = Supervisor::new();
+
+    // Outbound services share one client state (mTLS identity and connection pools)
+    let needs_client = config.client.enabled || config.socks.enabled || !config.forward.is_empty();
+    let client_state = if needs_client {
+        Some(Arc::new(
+            ClientState::new(&config).context("Failed to set up client")?,
+        ))
+    } else {
+        None
+    };
+    // The auth service and the inbound proxy share the authorization settings
+    let auth_state = if config.auth.enabled || config.inbound.enabled {
+        Some(Arc::new(
+            AuthState::new(&config).context("Failed to set up authorization")?,
+        ))
+    } else {
+        None
+    };
+
+    // Shared with the reloader, which swaps in renewed certificates
+    let inbound_tls = config
+        .inbound
+        .enabled
+        .then(|| Arc::new(InboundTls::default()));
+
+    let readiness = Arc::new(Readiness::new(shared.clone()));
+
+    // Start the enabled services - each Rocket service creates its own figment internally
+    if let Some(client_state) = &client_state {
+        if config.client.enabled {
+            let shared = shared.clone();
+            let state = client_state.clone();
+            let readiness = readiness.clone();
+            supervisor.spawn("client", move |shutdown| {
+                let loaded = shared.current();
+                let state = state.clone();
+                let readiness = readiness.clone();
+                async move {
+                    client::run_client_proxy(
+                        &loaded.figment,
+                        &loaded.config.client,
+                        state,
+                        readiness,
+                        shutdown,
+                    )
+                    .await
+                }
+            });
         }
-        result = server::run_auth_service(&figment) => {
-            result.context("Auth service failed")?;
+        if config.socks.enabled {
+            let shared = shared.clone();
+            let state = client_state.clone();
+            supervisor.spawn("socks", move |shutdown| {
+                let loaded = shared.current();
+                let state = state.clone();
+                async move { socks::run_socks_listener(&loaded.config.socks, state, shutdown).await }
+            });
+        }
+        for forward in &config.forward {
+            let forward = forward.clone();
+            let state = client_state.clone();
+            supervisor.spawn(format!("forward:{}", forward.listen), move |shutdown| {
+                let forward = forward.clone();
+                let state = state.clone();
+                async move { tunnel::run_port_forward(&forward, state, shutdown).await }
+            });
         }
     }
+    if let Some(auth_state) = &auth_state {
+        if let Some(tls) = &inbound_tls {
+            let shared = shared.clone();
+            let state = auth_state.clone();
+            let tls = tls.clone();
+            supervisor.spawn("inbound", move |shutdown| {
+                let loaded = shared.current();
+                let state = state.clone();
+                let tls = tls.clone();
+                async move {
+                    inbound::run_inbound_proxy(&loaded.config, state, tls, shutdown).await
+                }
+            });
+        }
+        if config.auth.enabled {
+            let shared = shared.clone();
+            let state = auth_state.clone();
+            let readiness = readiness.clone();
+            supervisor.spawn("auth", move |shutdown| {
+                let loaded = shared.current();
+                let state = state.clone();
+                let readiness = readiness.clone();
+                async move {
+                    server::run_auth_service(
+                        &loaded.figment,
+                        &loaded.config,
+                        state,
+                        readiness,
+                        shutdown,
+                    )
+                    .await
+                }
+            });
+        }
+    }
+
+    if supervisor.is_empty() {
+        bail!("No services enabled");
+    }
 
-    Ok(())
+    let reloader = Arc::new(Reloader::new(
+        args.config,
+        shared.clone(),
+        client_state,
+        auth_state,
+        inbound_tls,
+        supervisor.control(),
+    ));
+    if config.certs.renew {
+        let shared = shared.clone();
+        let reloader = reloader.clone();
+        supervisor.spawn("certs", move |shutdown| {
+            let shared = shared.clone();
+            let reloader = reloader.clone();
+            async move { certs::run_renewer(shared, reloader, shutdown).await }
+        });
+    }
+    if config.admin.enabled {
+        let reloader = reloader.clone();
+        let statuses = supervisor.statuses();
+        let control = supervisor.control();
+        supervisor.spawn("admin", move |shutdown| {
+            let loaded = shared.current();
+            let state = admin::AdminState {
+                config: shared.clone(),
+                reloader: reloader.clone(),
+                statuses: statuses.clone(),
+                control: control.clone(),
+            };
+            async move {
+                admin::run_admin_api(&loaded.figment, &loaded.config.admin, state, shutdown).await
+            }
+        });
+    }
+    supervisor.spawn("reload", move |shutdown| {
+        let reloader = reloader.clone();
+        async move { reloader.run(shutdown).await }
+    });
+    supervisor.run().await
 }
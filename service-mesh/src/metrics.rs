@@ -0,0 +1,358 @@
+use prometheus::core::Collector;
+use prometheus::{
+    HistogramOpts, HistogramVec, IntCounterVec, IntGauge, IntGaugeVec, Opts, Registry, TextEncoder,
+};
+use rocket::get;
+use rocket::http::{ContentType, Status};
+use rustls::{CertificateError, Error as TlsError};
+use std::collections::{HashMap, HashSet};
+use std::error::Error as StdError;
+use std::sync::{LazyLock, Mutex};
+use std::time::Duration;
+use tracing::error;
+
+/// Registry holding every dstack-mesh metric
+pub static REGISTRY: LazyLock<Registry> = LazyLock::new(Registry::new);
+
+/// app_ids that completed a verified RA-TLS handshake, with the instance ids their
+/// certificates carried. Only these become `target_app` and `instance` label values, so
+/// callers cannot grow the metrics with made-up ids; the map is bounded by the apps
+/// holding a certificate from the mesh CA.
+static VERIFIED_APPS: LazyLock<Mutex<HashMap<String, HashSet<String>>>> =
+    LazyLock::new(Default::default);
+
+/// Proxied requests by target, labelled `target_app`, `port`, `method` and `status_class`
+pub static REQUESTS: LazyLock<IntCounterVec> = LazyLock::new(|| {
+    register(
+        IntCounterVec::new(
+            Opts::new(
+                "dstack_mesh_requests_total",
+                "Requests proxied to mesh targets",
+            ),
+            &["target_app", "port", "method", "status_class"],
+        )
+        .expect("valid metric"),
+    )
+});
+
+/// Time until the upstream response headers arrived, with the labels of [`REQUESTS`]
+pub static REQUEST_DURATION: LazyLock<HistogramVec> = LazyLock::new(|| {
+    register(
+        HistogramVec::new(
+            HistogramOpts::new(
+                "dstack_mesh_request_duration_seconds",
+                "Time until the upstream response headers arrived",
+            ),
+            &["target_app", "port", "method", "status_class"],
+        )
+        .expect("valid metric"),
+    )
+});
+
+/// Failed upstream connections and requests, labelled `target_app` and `kind`
+pub static UPSTREAM_ERRORS: LazyLock<IntCounterVec> = LazyLock::new(|| {
+    register(
+        IntCounterVec::new(
+            Opts::new(
+                "dstack_mesh_upstream_errors_total",
+                "Failed upstream connections and requests by kind",
+            ),
+            &["target_app", "kind"],
+        )
+        .expect("valid metric"),
+    )
+});
+
+/// Retried upstream calls, labelled `target_app` and `reason`: the error kind or status
+/// that was retried, or `budget_exhausted` for retries skipped by the retry budget
+pub static RETRIES: LazyLock<IntCounterVec> = LazyLock::new(|| {
+    register(
+        IntCounterVec::new(
+            Opts::new(
+                "dstack_mesh_retries_total",
+                "Retried upstream calls by reason",
+            ),
+            &["target_app", "reason"],
+        )
+        .expect("valid metric"),
+    )
+});
+
+/// Circuit breaker state per target: 0 closed, 1 open, 2 half-open
+pub static CIRCUIT_STATE: LazyLock<IntGaugeVec> = LazyLock::new(|| {
+    register(
+        IntGaugeVec::new(
+            Opts::new(
+                "dstack_mesh_circuit_state",
+                "Circuit breaker state per target (0 closed, 1 open, 2 half-open)",
+            ),
+            &["target_app", "instance", "port"],
+        )
+        .expect("valid metric"),
+    )
+});
+
+/// Circuit breaker transitions, labelled `target_app` and the new `state`
+pub static CIRCUIT_TRANSITIONS: LazyLock<IntCounterVec> = LazyLock::new(|| {
+    register(
+        IntCounterVec::new(
+            Opts::new(
+                "dstack_mesh_circuit_transitions_total",
+                "Circuit breaker transitions by new state",
+            ),
+            &["target_app", "state"],
+        )
+        .expect("valid metric"),
+    )
+});
+
+/// Authorization decisions, labelled `caller_app` and `decision` (`allow` or `deny`)
+pub static AUTH_DECISIONS: LazyLock<IntCounterVec> = LazyLock::new(|| {
+    register(
+        IntCounterVec::new(
+            Opts::new(
+                "dstack_mesh_auth_decisions_total",
+                "Authorization decisions per caller app_id",
+            ),
+            &["caller_app", "decision"],
+        )
+        .expect("valid metric"),
+    )
+});
+
+/// Body and tunnel bytes, labelled `direction`: `upstream` towards the target,
+/// `downstream` back to the caller
+pub static BYTES_STREAMED: LazyLock<IntCounterVec> = LazyLock::new(|| {
+    register(
+        IntCounterVec::new(
+            Opts::new(
+                "dstack_mesh_bytes_streamed_total",
+                "Bytes streamed through the proxies",
+            ),
+            &["direction"],
+        )
+        .expect("valid metric"),
+    )
+});
+
+/// Expiry of the certificate in `tls.cert_file`
+pub static CERT_NOT_AFTER: LazyLock<IntGauge> = LazyLock::new(|| {
+    register(
+        IntGauge::new(
+            "dstack_mesh_cert_not_after_seconds",
+            "Unix time at which the certificate in tls.cert_file expires",
+        )
+        .expect("valid metric"),
+    )
+});
+
+/// Configuration and certificate reloads, labelled by `result` (`success` or `failure`)
+pub static RELOADS: LazyLock<IntCounterVec> = LazyLock::new(|| {
+    register(
+        IntCounterVec::new(
+            Opts::new(
+                "dstack_mesh_reloads_total",
+                "Configuration and certificate reloads by result",
+            ),
+            &["result"],
+        )
+        .expect("valid metric"),
+    )
+});
+
+/// Unix time of the last successful reload
+pub static LAST_RELOAD_SUCCESS: LazyLock<IntGauge> = LazyLock::new(|| {
+    register(
+        IntGauge::new(
+            "dstack_mesh_last_reload_success_timestamp_seconds",
+            "Unix time of the last successful reload",
+        )
+        .expect("valid metric"),
+    )
+});
+
+fn register<M: Collector + Clone + 'static>(metric: M) -> M {
+    REGISTRY
+        .register(Box::new(metric.clone()))
+        .expect("metric registered twice");
+    metric
+}
+
+/// Register every metric up front so that they are exported before first use
+pub fn init() {
+    LazyLock::force(&REQUESTS);
+    LazyLock::force(&REQUEST_DURATION);
+    LazyLock::force(&UPSTREAM_ERRORS);
+    LazyLock::force(&RETRIES);
+    LazyLock::force(&CIRCUIT_STATE);
+    LazyLock::force(&CIRCUIT_TRANSITIONS);
+    LazyLock::force(&AUTH_DECISIONS);
+    LazyLock::force(&BYTES_STREAMED);
+    LazyLock::force(&CERT_NOT_AFTER);
+    LazyLock::force(&RELOADS);
+    LazyLock::force(&LAST_RELOAD_SUCCESS);
+}
+
+/// Allow `app_id`, and the instance id of its certificate, as label values once the
+/// certificate checked out
+pub fn mark_verified(app_id: &str, instance_id: Option<&str>) {
+    let mut apps = VERIFIED_APPS.lock().unwrap_or_else(|e| e.into_inner());
+    let instances = apps.entry(app_id.to_lowercase()).or_default();
+    if let Some(instance_id) = instance_id {
+        instances.insert(instance_id.to_lowercase());
+    }
+}
+
+/// `target_app` label value: the app_id if a handshake with it was verified, else `invalid`
+fn target_label(app_id: &str) -> String {
+    let app_id = app_id.to_lowercase();
+    let apps = VERIFIED_APPS.lock().unwrap_or_else(|e| e.into_inner());
+    if apps.contains_key(&app_id) {
+        app_id
+    } else {
+        "invalid".to_string()
+    }
+}
+
+/// `instance` label value: the instance id if a verified certificate of the app
+/// carried it, else empty
+fn instance_label(app_id: &str, instance_id: &str) -> String {
+    let instance_id = instance_id.to_lowercase();
+    let apps = VERIFIED_APPS.lock().unwrap_or_else(|e| e.into_inner());
+    let verified = apps
+        .get(&app_id.to_lowercase())
+        .is_some_and(|instances| instances.contains(&instance_id));
+    if verified {
+        instance_id
+    } else {
+        String::new()
+    }
+}
+
+/// Record a proxied request that got `status` after `elapsed`
+pub fn record_request(app_id: &str, port: u16, method: &str, status: u16, elapsed: Duration) {
+    let app_id = target_label(app_id);
+    let port = port.to_string();
+    let status_class = format!("{}xx", status / 100);
+    let labels: [&str; 4] = [&app_id, &port, method, &status_class];
+    REQUESTS.with_label_values(&labels).inc();
+    REQUEST_DURATION
+        .with_label_values(&labels)
+        .observe(elapsed.as_secs_f64());
+}
+
+pub fn record_upstream_error(app_id: &str, kind: &str) {
+    UPSTREAM_ERRORS
+        .with_label_values(&[target_label(app_id).as_str(), kind])
+        .inc();
+}
+
+pub fn record_retry(app_id: &str, reason: &str) {
+    RETRIES
+        .with_label_values(&[target_label(app_id).as_str(), reason])
+        .inc();
+}
+
+pub fn record_circuit_state(app_id: &str, instance_id: &str, port: u16, state: &str, value: i64) {
+    let instance_id = instance_label(app_id, instance_id);
+    let app_id = target_label(app_id);
+    CIRCUIT_STATE
+        .with_label_values(&[&app_id, &instance_id, &port.to_string()])
+        .set(value);
+    CIRCUIT_TRANSITIONS
+        .with_label_values(&[&app_id, state])
+        .inc();
+}
+
+pub fn record_auth(app_id: &str, allowed: bool) {
+    let decision = if allowed { "allow" } else { "deny" };
+    AUTH_DECISIONS
+        .with_label_values(&[app_id.to_lowercase().as_str(), decision])
+        .inc();
+}
+
+pub fn record_bytes(upstream: u64, downstream: u64) {
+    BYTES_STREAMED
+        .with_label_values(&["upstream"])
+        .inc_by(upstream);
+    BYTES_STREAMED
+        .with_label_values(&["downstream"])
+        .inc_by(downstream);
+}
+
+/// Classify an upstream failure by walking its error chain
+pub fn upstream_error_kind(err: &(dyn StdError + 'static)) -> &'static str {
+    let mut kind = "other";
+    let mut source = Some(err);
+    while let Some(err) = source {
+        if err.is::<tokio::time::error::Elapsed>() {
+            return "connect_timeout";
+        }
+        if let Some(err) = err.downcast_ref::<reqwest::Error>() {
+            if err.is_timeout() {
+                return "connect_timeout";
+            }
+            if err.is_connect() {
+                kind = "connect";
+            }
+        }
+        if let Some(err) = err.downcast_ref::<std::io::Error>() {
+            if err.kind() == std::io::ErrorKind::TimedOut {
+                return "connect_timeout";
+            }
+            // `io::Error::source` skips the wrapped error, so inspect it directly
+            if let Some(err) = err.get_ref().and_then(|e| e.downcast_ref::<TlsError>()) {
+                return tls_error_kind(err);
+            }
+        }
+        if let Some(err) = err.downcast_ref::<TlsError>() {
+            return tls_error_kind(err);
+        }
+        source = err.source();
+    }
+    kind
+}
+
+fn tls_error_kind(err: &TlsError) -> &'static str {
+    match err {
+        // Raised by our verifier for an unexpected app_id or measurements
+        TlsError::InvalidCertificate(CertificateError::ApplicationVerificationFailure) => {
+            "app_id_mismatch"
+        }
+        _ => "tls",
+    }
+}
+
+/// Prometheus text exposition of every metric
+#[get("/metrics")]
+pub fn metrics_handler() -> Result<(ContentType, String), Status> {
+    render()
+}
+
+pub fn render() -> Result<(ContentType, String), Status> {
+    TextEncoder::new()
+        .encode_to_string(&REGISTRY.gather())
+        .map(|body| (ContentType::Plain, body))
+        .map_err(|e| {
+            error!("Failed to encode metrics: {e}");
+            Status::InternalServerError
+        })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn labels_only_verified_ids() {
+        let app_id = "4444444444444444444444444444444444444444";
+        assert_eq!(target_label(app_id), "invalid");
+        assert_eq!(instance_label(app_id, "aa"), "");
+
+        mark_verified(app_id, Some("AA"));
+        assert_eq!(target_label(&app_id.to_uppercase()), app_id);
+        assert_eq!(instance_label(app_id, "aa"), "aa");
+        assert_eq!(instance_label(app_id, "bb"), "");
+        assert_eq!(instance_label("5555", "aa"), "");
+    }
+}
@@ -0,0 +1,187 @@
+use anyhow::{Context, Result};
+use std::io;
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{Context as TaskContext, Poll};
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, ReadBuf};
+use tokio::net::{TcpListener, TcpStream, UnixStream};
+use tokio_util::sync::CancellationToken;
+use tokio_util::task::TaskTracker;
+use tracing::{debug, info, warn};
+
+use crate::client::ClientState;
+use crate::grpc::{self, UpstreamPool};
+use crate::supervisor::drain_connections;
+use crate::tunnel;
+
+/// How long a new connection may take to send the bytes that identify its protocol
+const SNIFF_TIMEOUT: Duration = Duration::from_secs(10);
+const CONNECT_PREFIX: &[u8] = b"CONNECT ";
+/// Connection preface of HTTP/2 with prior knowledge (RFC 9113, section 3.4)
+const H2_PREFACE: &[u8] = b"PRI * HTTP/2.0\r\n\r\nSM\r\n\r\n";
+
+/// What a client connection speaks, judged by its first bytes
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Protocol {
+    /// An HTTP/1.1 `CONNECT` request, served by the tunnel handler
+    Connect,
+    /// h2c with prior knowledge, e.g. gRPC, served by the HTTP/2 handler
+    Http2,
+    /// Any other HTTP/1.x request, served by Rocket
+    Http1,
+}
+
+/// Accept connections on the client proxy address and dispatch them by protocol.
+///
+/// Rocket cannot serve `CONNECT` or HTTP/2 without TLS, so connections that open
+/// with a `CONNECT` go to the tunnel handler and those that open with the h2c preface
+/// to the HTTP/2 handler. Everything else is spliced to Rocket, which listens on the
+/// private unix socket `backend`.
+pub async fn run_client_listener(
+    addr: SocketAddr,
+    backend: PathBuf,
+    state: Arc<ClientState>,
+    shutdown: CancellationToken,
+) -> Result<()> {
+    let listener = TcpListener::bind(addr)
+        .await
+        .with_context(|| format!("Failed to bind client proxy on {addr}"))?;
+    info!("Client proxy listening on {addr}");
+
+    let backend: Arc<Path> = backend.into();
+    let pool = Arc::new(UpstreamPool::default());
+    let tracker = TaskTracker::new();
+    loop {
+        let (stream, peer) = tokio::select! {
+            accepted = listener.accept() => accepted.context("Failed to accept client connection")?,
+            _ = shutdown.cancelled() => break,
+        };
+        let state = state.clone();
+        let backend = backend.clone();
+        let pool = pool.clone();
+        let relays = tracker.clone();
+        tracker.spawn(async move {
+            if let Err(e) = serve(stream, &backend, state, pool, relays).await {
+                debug!("Client connection from {peer} failed: {e:#}");
+            }
+        });
+    }
+    drain_connections(tracker, "client").await;
+    Ok(())
+}
+
+async fn serve(
+    mut stream: TcpStream,
+    backend: &Path,
+    state: Arc<ClientState>,
+    pool: Arc<UpstreamPool>,
+    relays: TaskTracker,
+) -> Result<()> {
+    let (protocol, prefix) = tokio::time::timeout(SNIFF_TIMEOUT, sniff(&mut stream))
+        .await
+        .context("Timed out waiting for the request")??;
+    let stream = Rewind::new(prefix, stream);
+    match protocol {
+        Protocol::Connect => tunnel::serve_connect(stream, state, relays).await?,
+        Protocol::Http2 => grpc::serve_h2c(stream, state, pool).await?,
+        Protocol::Http1 => splice(stream, backend).await?,
+    }
+    Ok(())
+}
+
+/// Read just enough of the connection to tell its protocol. Returns the bytes read,
+/// which have to be replayed to whichever handler serves the connection.
+async fn sniff(stream: &mut TcpStream) -> Result<(Protocol, Vec<u8>)> {
+    let signatures = [
+        (Protocol::Connect, CONNECT_PREFIX),
+        (Protocol::Http2, H2_PREFACE),
+    ];
+    let mut prefix = Vec::with_capacity(H2_PREFACE.len());
+    loop {
+        // The signatures differ in their first byte, so at most one is left after it
+        let Some(&(protocol, signature)) = signatures
+            .iter()
+            .find(|(_, signature)| signature.starts_with(&prefix))
+        else {
+            return Ok((Protocol::Http1, prefix));
+        };
+        if prefix.len() == signature.len() {
+            return Ok((protocol, prefix));
+        }
+        let mut buf = [0; H2_PREFACE.len()];
+        let n = stream
+            .read(&mut buf[..signature.len() - prefix.len()])
+            .await?;
+        if n == 0 {
+            // Closed before a full request line; let the backend answer whatever came
+            return Ok((Protocol::Http1, prefix));
+        }
+        prefix.extend_from_slice(&buf[..n]);
+    }
+}
+
+/// Copy the connection to Rocket's unix socket and back
+async fn splice(mut stream: Rewind<TcpStream>, backend: &Path) -> Result<()> {
+    let mut upstream = UnixStream::connect(backend).await.map_err(|e| {
+        warn!("Client proxy backend is not accepting connections: {e}");
+        e
+    })?;
+    tokio::io::copy_bidirectional(&mut stream, &mut upstream).await?;
+    let _ = upstream.shutdown().await;
+    Ok(())
+}
+
+/// A stream that yields `prefix` before the rest of `inner`
+pub(crate) struct Rewind<S> {
+    prefix: Vec<u8>,
+    offset: usize,
+    inner: S,
+}
+
+impl<S> Rewind<S> {
+    pub(crate) fn new(prefix: Vec<u8>, inner: S) -> Self {
+        Self {
+            prefix,
+            offset: 0,
+            inner,
+        }
+    }
+}
+
+impl<S: AsyncRead + Unpin> AsyncRead for Rewind<S> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut TaskContext<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        if self.offset < self.prefix.len() {
+            let remaining = &self.prefix[self.offset..];
+            let n = remaining.len().min(buf.remaining());
+            buf.put_slice(&remaining[..n]);
+            self.offset += n;
+            return Poll::Ready(Ok(()));
+        }
+        Pin::new(&mut self.inner).poll_read(cx, buf)
+    }
+}
+
+impl<S: AsyncWrite + Unpin> AsyncWrite for Rewind<S> {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut TaskContext<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.inner).poll_write(cx, buf)
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut TaskContext<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut TaskContext<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_shutdown(cx)
+    }
+}
@@ -0,0 +1,240 @@
+use anyhow::{bail, Result};
+use serde::{Deserialize, Serialize};
+use std::borrow::Cow;
+
+use crate::identity::PeerIdentity;
+
+/// Authorization rule granting callers access to paths and methods
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct PolicyRule {
+    /// Caller app_ids the rule applies to; `*` matches any caller
+    pub apps: Vec<String>,
+    /// Allowed path prefixes; empty allows every path
+    #[serde(default)]
+    pub paths: Vec<String>,
+    /// Allowed HTTP methods; empty allows every method
+    #[serde(default)]
+    pub methods: Vec<String>,
+}
+
+impl PolicyRule {
+    fn matches_app(&self, app_id: &str) -> bool {
+        self.apps
+            .iter()
+            .any(|app| app == "*" || app.eq_ignore_ascii_case(app_id))
+    }
+
+    fn matches_method(&self, method: &str) -> bool {
+        self.methods.is_empty() || self.methods.iter().any(|m| m.eq_ignore_ascii_case(method))
+    }
+
+    fn matches_path(&self, path: &str) -> bool {
+        self.paths.is_empty()
+            || self
+                .paths
+                .iter()
+                .any(|prefix| path_has_prefix(path, prefix))
+    }
+}
+
+/// Measurements an outbound target must match, checked against its RA-TLS certificate.
+///
+/// Empty lists do not constrain the corresponding attribute.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct PeerPolicy {
+    pub app_id: String,
+    #[serde(default)]
+    pub compose_hashes: Vec<String>,
+    #[serde(default)]
+    pub os_image_hashes: Vec<String>,
+    #[serde(default)]
+    pub instance_ids: Vec<String>,
+}
+
+impl PeerPolicy {
+    /// Fail unless the peer's measurements are all in the allowlists
+    pub fn check(&self, peer: &PeerIdentity) -> Result<()> {
+        check_allowed("compose hash", &self.compose_hashes, &peer.compose_hash)?;
+        check_allowed("OS image hash", &self.os_image_hashes, &peer.os_image_hash)?;
+        check_allowed("instance id", &self.instance_ids, &peer.instance_id)?;
+        Ok(())
+    }
+}
+
+fn check_allowed(what: &str, allowed: &[String], actual: &Option<String>) -> Result<()> {
+    if allowed.is_empty() {
+        return Ok(());
+    }
+    let Some(actual) = actual else {
+        bail!("Peer certificate has no {what}");
+    };
+    if !allowed.iter().any(|a| a.eq_ignore_ascii_case(actual)) {
+        bail!("Peer {what} '{actual}' is not in the allowlist");
+    }
+    Ok(())
+}
+
+/// Outcome of evaluating the policy for a request
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Decision {
+    /// No rules are configured, every authenticated caller is allowed
+    Unrestricted,
+    /// Allowed by the rule at the given index
+    Allowed(usize),
+    /// No rule allows the request
+    Denied,
+}
+
+/// Evaluate `rules` for a caller requesting `uri` with `method`.
+///
+/// The URI is normalized (query stripped, percent-decoded, dot segments resolved)
+/// before matching so that e.g. `/public/../admin` cannot sneak past a prefix rule.
+pub fn evaluate(rules: &[PolicyRule], app_id: &str, method: &str, uri: &str) -> Decision {
+    if rules.is_empty() {
+        return Decision::Unrestricted;
+    }
+    let Some(path) = normalize_path(uri) else {
+        return Decision::Denied;
+    };
+    rules
+        .iter()
+        .position(|rule| {
+            rule.matches_app(app_id) && rule.matches_method(method) && rule.matches_path(&path)
+        })
+        .map(Decision::Allowed)
+        .unwrap_or(Decision::Denied)
+}
+
+/// Prefix match on path segment boundaries: `/admin` matches `/admin` and `/admin/x`
+/// but not `/administrator`
+fn path_has_prefix(path: &str, prefix: &str) -> bool {
+    let Some(rest) = path.strip_prefix(prefix) else {
+        return false;
+    };
+    prefix.ends_with('/') || rest.is_empty() || rest.starts_with('/')
+}
+
+fn normalize_path(uri: &str) -> Option<String> {
+    let path = uri.split(['?', '#']).next().unwrap_or_default();
+    let decoded: Cow<str> = urlencoding::decode(path).ok()?;
+
+    let mut segments: Vec<&str> = Vec::new();
+    for segment in decoded.split('/') {
+        match segment {
+            "" | "." => {}
+            ".." => {
+                segments.pop();
+            }
+            segment => segments.push(segment),
+        }
+    }
+    let mut normalized = format!("/{}", segments.join("/"));
+    if decoded.ends_with('/') && normalized.len() > 1 {
+        normalized.push('/');
+    }
+    Some(normalized)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn rule(apps: &[&str], paths: &[&str], methods: &[&str]) -> PolicyRule {
+        let strings = |values: &[&str]| values.iter().map(|v| v.to_string()).collect();
+        PolicyRule {
+            apps: strings(apps),
+            paths: strings(paths),
+            methods: strings(methods),
+        }
+    }
+
+    #[test]
+    fn no_rules_is_unrestricted() {
+        assert_eq!(
+            evaluate(&[], "app", "GET", "/admin"),
+            Decision::Unrestricted
+        );
+    }
+
+    #[test]
+    fn prefix_matches_on_segment_boundaries() {
+        assert!(path_has_prefix("/admin", "/admin"));
+        assert!(path_has_prefix("/admin/", "/admin"));
+        assert!(path_has_prefix("/admin/users", "/admin"));
+        assert!(!path_has_prefix("/administrator", "/admin"));
+        assert!(!path_has_prefix("/admin-panel", "/admin"));
+        assert!(path_has_prefix("/api/v1", "/api/"));
+        assert!(!path_has_prefix("/api", "/api/"));
+        assert!(path_has_prefix("/anything", "/"));
+    }
+
+    #[test]
+    fn normalizes_dot_segments_and_slashes() {
+        let normalize = |uri| normalize_path(uri).unwrap();
+        assert_eq!(normalize("/public/../admin"), "/admin");
+        assert_eq!(normalize("/public/./../../admin"), "/admin");
+        assert_eq!(normalize("/../../admin"), "/admin");
+        assert_eq!(normalize("//admin"), "/admin");
+        assert_eq!(normalize("/public//..//admin"), "/admin");
+        assert_eq!(normalize("/a/b/"), "/a/b/");
+        assert_eq!(normalize(""), "/");
+        assert_eq!(normalize("/admin?next=/public"), "/admin");
+        assert_eq!(normalize("/admin#/public"), "/admin");
+    }
+
+    #[test]
+    fn decodes_percent_encoding_before_normalizing() {
+        let normalize = |uri| normalize_path(uri).unwrap();
+        assert_eq!(normalize("/public/%2e%2e/admin"), "/admin");
+        assert_eq!(normalize("/public/%2E%2E/admin"), "/admin");
+        assert_eq!(normalize("/admin%2fsecret"), "/admin/secret");
+        assert_eq!(normalize("/%61dmin"), "/admin");
+        // Not valid UTF-8 once decoded
+        assert_eq!(normalize_path("/admin%ff"), None);
+    }
+
+    #[test]
+    fn traversal_cannot_escape_a_prefix_rule() {
+        let rules = [rule(&["*"], &["/public"], &[])];
+        let eval = |uri| evaluate(&rules, "app", "GET", uri);
+        assert_eq!(eval("/public/index.html"), Decision::Allowed(0));
+        assert_eq!(eval("/public/../admin"), Decision::Denied);
+        assert_eq!(eval("/public/%2e%2e/admin"), Decision::Denied);
+        assert_eq!(eval("/public%2f..%2fadmin"), Decision::Denied);
+        assert_eq!(eval("//public/../admin"), Decision::Denied);
+        assert_eq!(eval("/publicity"), Decision::Denied);
+        assert_eq!(eval("/public%ff"), Decision::Denied);
+    }
+
+    #[test]
+    fn restricted_prefix_is_not_reachable_by_other_apps() {
+        let rules = [
+            rule(&["admin-app"], &["/admin"], &[]),
+            rule(&["*"], &["/api"], &["GET", "POST"]),
+        ];
+        assert_eq!(
+            evaluate(&rules, "ADMIN-APP", "DELETE", "/admin/users"),
+            Decision::Allowed(0)
+        );
+        assert_eq!(
+            evaluate(&rules, "other", "GET", "/admin/users"),
+            Decision::Denied
+        );
+        assert_eq!(
+            evaluate(&rules, "other", "GET", "/api/../admin"),
+            Decision::Denied
+        );
+        assert_eq!(
+            evaluate(&rules, "other", "post", "/api/items"),
+            Decision::Allowed(1)
+        );
+        assert_eq!(
+            evaluate(&rules, "other", "DELETE", "/api/items"),
+            Decision::Denied
+        );
+        assert_eq!(
+            evaluate(&rules, "other", "GET", "/administrator"),
+            Decision::Denied
+        );
+    }
+}
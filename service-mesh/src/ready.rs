@@ -0,0 +1,197 @@
+use anyhow::{anyhow, bail, Context, Result};
+use rocket::http::Status;
+use rocket::serde::json::Json;
+use rocket::{get, Either, State};
+use rustls::pki_types::pem::PemObject;
+use rustls::pki_types::{CertificateDer, ServerName};
+use serde::Serialize;
+use std::future::Future;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::net::TcpStream;
+use tokio_rustls::TlsConnector;
+use tracing::debug;
+
+use crate::agent::AgentClient;
+use crate::certs::{cert_validity, unix_now};
+use crate::client::gateway_authority;
+use crate::config::{Config, SharedConfig, TargetInfo};
+use crate::identity::PeerIdentity;
+use crate::tls::{probe_client_config, TlsMaterial};
+
+/// Checks whether the mesh dependencies are usable
+pub struct Readiness {
+    config: SharedConfig,
+}
+
+#[derive(Debug, Serialize)]
+pub struct Check {
+    name: &'static str,
+    ok: bool,
+    detail: String,
+}
+
+#[derive(Debug, Serialize)]
+pub struct Report {
+    ready: bool,
+    checks: Vec<Check>,
+}
+
+impl Readiness {
+    pub fn new(config: SharedConfig) -> Self {
+        Self { config }
+    }
+
+    /// Run every check against the current configuration
+    pub async fn check(&self) -> Report {
+        let loaded = self.config.current();
+        let config = &loaded.config;
+        let timeout = Duration::from_secs(config.ready.timeout_secs);
+
+        let (agent, gateway) = tokio::join!(
+            with_timeout(timeout, check_agent()),
+            with_timeout(timeout, check_gateway(config)),
+        );
+        let checks = vec![
+            to_check("certificate", check_certificate(config)),
+            to_check("ca", check_ca(config)),
+            to_check("agent", agent),
+            to_check("gateway", gateway),
+        ];
+        Report {
+            ready: checks.iter().all(|check| check.ok),
+            checks,
+        }
+    }
+}
+
+fn to_check(name: &'static str, result: Result<String>) -> Check {
+    match result {
+        Ok(detail) => Check {
+            name,
+            ok: true,
+            detail,
+        },
+        Err(err) => Check {
+            name,
+            ok: false,
+            detail: format!("{err:#}"),
+        },
+    }
+}
+
+async fn with_timeout(
+    timeout: Duration,
+    check: impl Future<Output = Result<String>>,
+) -> Result<String> {
+    tokio::time::timeout(timeout, check)
+        .await
+        .unwrap_or_else(|_| Err(anyhow!("Timed out after {timeout:?}")))
+}
+
+/// The certificate is currently valid and does not expire too soon
+fn check_certificate(config: &Config) -> Result<String> {
+    let (not_before, not_after) = cert_validity(&config.tls.cert_file)?;
+    let now = unix_now();
+    if now < not_before {
+        bail!("Certificate is not valid before {not_before}");
+    }
+    let remaining = not_after - now;
+    if remaining < config.ready.cert_min_remaining_secs {
+        bail!("Certificate expires in {remaining}s");
+    }
+    Ok(format!("Certificate expires in {remaining}s"))
+}
+
+/// The key and certificate load and the certificate chains to `tls.ca_file`
+fn check_ca(config: &Config) -> Result<String> {
+    TlsMaterial::load(&config.tls)?.verify_own_chain()?;
+    Ok(format!("Certificate chains to {}", config.tls.ca_file))
+}
+
+async fn check_agent() -> Result<String> {
+    AgentClient::new()?.ping().await?;
+    Ok("Agent is responding".to_string())
+}
+
+/// The gateway resolves and completes a TLS handshake
+async fn check_gateway(config: &Config) -> Result<String> {
+    let gateway_domain = config.dstack.gateway_domain.trim_end_matches('/');
+    let (host, port) = match gateway_domain.strip_prefix("fixed/") {
+        Some(fixed) => match fixed.rsplit_once(':') {
+            Some((host, port)) => (
+                host.to_string(),
+                port.parse().context("Invalid gateway port")?,
+            ),
+            None => (fixed.to_string(), 443),
+        },
+        None => (gateway_probe_host(config)?, 443),
+    };
+    let host = host.as_str();
+
+    let addr = tokio::net::lookup_host((host, port))
+        .await
+        .with_context(|| format!("Failed to resolve {host}"))?
+        .next()
+        .with_context(|| format!("{host} has no addresses"))?;
+    let tcp = TcpStream::connect(addr)
+        .await
+        .with_context(|| format!("Failed to connect to {addr}"))?;
+    // Only reachability is probed here; mesh peers are authenticated end to end
+    let server_name = ServerName::try_from(host.to_string()).context("Invalid gateway host")?;
+    TlsConnector::from(Arc::new(probe_client_config()?))
+        .connect(server_name, tcp)
+        .await
+        .context("TLS handshake failed")?;
+    Ok(format!("{host}:{port} ({addr}) completed a TLS handshake"))
+}
+
+/// `ready.gateway_probe_host`, or the name peers dial to reach our own app through
+/// the gateway. The bare `gateway_domain` is never dialed, so it may not resolve even
+/// while the gateway works.
+fn gateway_probe_host(config: &Config) -> Result<String> {
+    if let Some(host) = &config.ready.gateway_probe_host {
+        return Ok(host.clone());
+    }
+    let cert =
+        CertificateDer::from_pem_file(&config.tls.cert_file).context("Failed to read cert file")?;
+    let own = TargetInfo {
+        app_id: PeerIdentity::from_der(&cert)?.app_id,
+        instance_id: String::new(),
+        port: 443,
+    };
+    Ok(gateway_authority(
+        &config.dstack.gateway_domain,
+        &own,
+        false,
+    ))
+}
+
+/// Readiness probe: `200` when every check passes, `503` otherwise.
+///
+/// `?verbose=true` adds a JSON report of the individual checks.
+#[get("/ready?<verbose>")]
+pub async fn ready_handler(
+    verbose: Option<bool>,
+    readiness: &State<Arc<Readiness>>,
+) -> Either<Status, (Status, Json<Report>)> {
+    respond(readiness, verbose).await
+}
+
+pub async fn respond(
+    readiness: &Readiness,
+    verbose: Option<bool>,
+) -> Either<Status, (Status, Json<Report>)> {
+    let report = readiness.check().await;
+    let status = if report.ready {
+        Status::Ok
+    } else {
+        debug!("Not ready: {report:?}");
+        Status::ServiceUnavailable
+    };
+    if verbose.unwrap_or(false) {
+        Either::Right((status, Json(report)))
+    } else {
+        Either::Left(status)
+    }
+}
@@ -0,0 +1,229 @@
+use anyhow::{Context, Result};
+use serde::Serialize;
+use std::sync::Arc;
+use std::time::{Duration, SystemTime};
+use tokio::signal::unix::{signal, SignalKind};
+use tokio::sync::Notify;
+use tokio_util::sync::CancellationToken;
+use tracing::{debug, error, info, warn};
+
+use crate::certs::{cert_validity, unix_now};
+use crate::client::{ClientRuntime, ClientState};
+use crate::config::{Config, LoadedConfig, SharedConfig, TlsConfig};
+use crate::inbound::InboundTls;
+use crate::metrics;
+use crate::server::{AuthSettings, AuthState};
+use crate::supervisor::ServiceControl;
+use crate::tls::TlsMaterial;
+
+/// Modification time and size of each watched file, `None` if it cannot be read
+type Fingerprint = Vec<Option<(SystemTime, u64)>>;
+
+/// Re-reads the configuration and TLS files and swaps the running state over to them.
+///
+/// Everything is rebuilt before anything is replaced, so a broken file leaves the
+/// previous state in place. Requests already in flight finish on the state they
+/// started with; only listeners whose address changed are gracefully restarted.
+pub struct Reloader {
+    config_file: Option<String>,
+    config: SharedConfig,
+    client: Option<Arc<ClientState>>,
+    auth: Option<Arc<AuthState>>,
+    inbound_tls: Option<Arc<InboundTls>>,
+    services: ServiceControl,
+    requested: Notify,
+}
+
+impl Reloader {
+    pub fn new(
+        config_file: Option<String>,
+        config: SharedConfig,
+        client: Option<Arc<ClientState>>,
+        auth: Option<Arc<AuthState>>,
+        inbound_tls: Option<Arc<InboundTls>>,
+        services: ServiceControl,
+    ) -> Self {
+        Self {
+            config_file,
+            config,
+            client,
+            auth,
+            inbound_tls,
+            services,
+            requested: Notify::new(),
+        }
+    }
+
+    /// Ask the running reloader to reload, e.g. after new certificates were written
+    pub fn request(&self) {
+        self.requested.notify_one();
+    }
+
+    /// Reload on SIGHUP, on [`Reloader::request`] and, when `reload.watch` is set, whenever a watched file changes
+    pub async fn run(&self, shutdown: CancellationToken) -> Result<()> {
+        let mut sighup =
+            signal(SignalKind::hangup()).context("Failed to install SIGHUP handler")?;
+        let mut config_seen = self.config_fingerprint();
+        let mut tls_seen = tls_fingerprint(&self.config.current().config.tls);
+        loop {
+            update_cert_expiry(&self.config.current().config.tls);
+            let settings = self.config.current().config.reload.clone();
+            let interval = Duration::from_secs(settings.interval_secs.max(1));
+            tokio::select! {
+                _ = shutdown.cancelled() => return Ok(()),
+                _ = sighup.recv() => {
+                    info!("Received SIGHUP, reloading");
+                    self.reload();
+                }
+                _ = self.requested.notified() => {
+                    info!("Reload requested");
+                    self.reload();
+                }
+                _ = tokio::time::sleep(interval), if settings.watch => {
+                    let tls_now = tls_fingerprint(&self.config.current().config.tls);
+                    if tls_now == tls_seen && self.config_fingerprint() == config_seen {
+                        continue;
+                    }
+                    info!("Watched files changed, reloading");
+                    self.reload();
+                }
+            }
+            // A failed reload is retried on the next change rather than on every poll
+            config_seen = self.config_fingerprint();
+            tls_seen = tls_fingerprint(&self.config.current().config.tls);
+        }
+    }
+
+    fn reload(&self) {
+        match self.try_reload() {
+            Ok(()) => {
+                info!("Reload succeeded");
+                metrics::RELOADS.with_label_values(&["success"]).inc();
+                metrics::LAST_RELOAD_SUCCESS.set(unix_now());
+            }
+            Err(err) => {
+                error!("Reload failed, keeping the previous configuration: {err:?}");
+                metrics::RELOADS.with_label_values(&["failure"]).inc();
+            }
+        }
+    }
+
+    fn try_reload(&self) -> Result<()> {
+        let loaded = LoadedConfig::load(self.config_file.as_deref())?;
+        let new = &loaded.config;
+
+        let client = match &self.client {
+            Some(_) => Some(ClientRuntime::new(new)?),
+            None => None,
+        };
+        let auth = match &self.auth {
+            Some(_) => Some(AuthSettings::load(new)?),
+            None => None,
+        };
+        let inbound_tls = match &self.inbound_tls {
+            Some(tls) => Some((
+                tls,
+                TlsMaterial::load(&new.tls).context("Failed to load TLS material")?,
+            )),
+            None => None,
+        };
+
+        let previous = self.config.current();
+        let restarts = listeners_to_restart(&previous.config, new);
+        warn_needs_restart(&previous.config, new);
+
+        // The running inbound listener serves new handshakes with the new files
+        if let Some((tls, material)) = inbound_tls {
+            tls.replace(&material)?;
+        }
+        if let (Some(state), Some(runtime)) = (&self.client, client) {
+            state.replace(runtime);
+        }
+        if let (Some(state), Some(settings)) = (&self.auth, auth) {
+            state.replace(settings);
+        }
+        self.config.replace(loaded);
+
+        for name in restarts {
+            if self.services.restart(name) {
+                info!("Restarting {name} listener to apply the new configuration");
+            }
+        }
+        Ok(())
+    }
+
+    fn config_fingerprint(&self) -> Fingerprint {
+        self.config_file
+            .iter()
+            .map(|path| file_state(path))
+            .collect()
+    }
+}
+
+fn update_cert_expiry(tls: &TlsConfig) {
+    match cert_validity(&tls.cert_file) {
+        Ok((_, not_after)) => metrics::CERT_NOT_AFTER.set(not_after),
+        Err(err) => debug!("Failed to read certificate expiry: {err:?}"),
+    }
+}
+
+fn tls_fingerprint(tls: &TlsConfig) -> Fingerprint {
+    [&tls.cert_file, &tls.key_file, &tls.ca_file]
+        .into_iter()
+        .map(|path| file_state(path))
+        .collect()
+}
+
+fn file_state(path: &str) -> Option<(SystemTime, u64)> {
+    let metadata = std::fs::metadata(path).ok()?;
+    Some((metadata.modified().ok()?, metadata.len()))
+}
+
+/// Services that have to rebind to pick up the change
+fn listeners_to_restart(old: &Config, new: &Config) -> Vec<&'static str> {
+    let mut restarts = vec![];
+    if (old.client.address, old.client.port) != (new.client.address, new.client.port) {
+        restarts.push("client");
+    }
+    if (old.socks.address, old.socks.port) != (new.socks.address, new.socks.port) {
+        restarts.push("socks");
+    }
+    if (
+        old.auth.address,
+        old.auth.port,
+        old.auth.loopback_only,
+        &old.auth.socket,
+    ) != (
+        new.auth.address,
+        new.auth.port,
+        new.auth.loopback_only,
+        &new.auth.socket,
+    ) {
+        restarts.push("auth");
+    }
+    if changed(&old.inbound, &new.inbound) {
+        restarts.push("inbound");
+    }
+    restarts
+}
+
+fn warn_needs_restart(old: &Config, new: &Config) {
+    let enabled = |c: &Config| {
+        (
+            c.client.enabled,
+            c.socks.enabled,
+            c.inbound.enabled,
+            c.auth.enabled,
+        )
+    };
+    if enabled(old) != enabled(new) {
+        warn!("Enabling or disabling services takes effect after a restart");
+    }
+    if changed(&old.forward, &new.forward) {
+        warn!("Changes to [[forward]] entries take effect after a restart");
+    }
+}
+
+fn changed<T: Serialize>(old: &T, new: &T) -> bool {
+    serde_json::to_value(old).ok() != serde_json::to_value(new).ok()
+}
@@ -0,0 +1,248 @@
+use rand::Rng;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+use tracing::warn;
+
+use crate::config::RetryConfig;
+use crate::metrics;
+
+/// How many seconds of `budget_min_per_sec` the budget can save up
+const BUDGET_WINDOW_SECS: f64 = 10.0;
+/// Retries the budget can always save up, even with a low `budget_min_per_sec`
+const MIN_BUDGET_CAPACITY: f64 = 10.0;
+
+/// Why an upstream attempt failed
+#[derive(Debug, Clone, Copy)]
+pub enum RetryReason {
+    /// Upstream error kind, see [`metrics::upstream_error_kind`]
+    Error(&'static str),
+    Status(u16),
+}
+
+impl RetryReason {
+    fn label(&self) -> String {
+        match self {
+            RetryReason::Error(kind) => kind.to_string(),
+            RetryReason::Status(status) => status.to_string(),
+        }
+    }
+}
+
+/// Decides whether and when failed upstream calls are tried again
+pub struct RetryPolicy {
+    config: RetryConfig,
+    budget: RetryBudget,
+}
+
+impl RetryPolicy {
+    pub fn new(config: &RetryConfig) -> Self {
+        Self {
+            config: config.clone(),
+            budget: RetryBudget::new(config.budget_ratio, config.budget_min_per_sec),
+        }
+    }
+
+    /// Whether a request can safely be sent more than once
+    pub fn is_retryable(&self, method: &str, has_idempotency_key: bool) -> bool {
+        self.config.max_attempts > 1
+            && (has_idempotency_key
+                || self
+                    .config
+                    .methods
+                    .iter()
+                    .any(|m| m.eq_ignore_ascii_case(method)))
+    }
+
+    /// Largest request body buffered so that it can be replayed
+    pub fn max_body_size(&self) -> u64 {
+        self.config.max_body_size.as_u64()
+    }
+
+    /// Account for a new request, which earns the budget a fraction of a retry
+    pub fn record_request(&self) {
+        self.budget.deposit();
+    }
+
+    /// Whether a request whose `attempt`-th try (starting at 1) failed should be
+    /// tried again. Takes a retry from the budget if so.
+    pub fn should_retry(&self, app_id: &str, attempt: u32, reason: RetryReason) -> bool {
+        if attempt >= self.config.max_attempts {
+            return false;
+        }
+        let matches = match reason {
+            RetryReason::Error(kind) => self.config.on_errors.iter().any(|k| k == kind),
+            RetryReason::Status(status) => self.config.on_statuses.contains(&status),
+        };
+        if !matches {
+            return false;
+        }
+        if !self.budget.withdraw() {
+            warn!("Retry budget exhausted, not retrying request to app_id '{app_id}'");
+            metrics::record_retry(app_id, "budget_exhausted");
+            return false;
+        }
+        metrics::record_retry(app_id, &reason.label());
+        true
+    }
+
+    /// Delay before the next attempt: exponential backoff with full jitter
+    pub fn backoff(&self, attempt: u32) -> Duration {
+        let exp = self
+            .config
+            .initial_backoff_ms
+            .saturating_mul(1 << attempt.saturating_sub(1).min(16));
+        let cap = exp.min(self.config.max_backoff_ms);
+        Duration::from_millis(rand::thread_rng().gen_range(0..=cap))
+    }
+}
+
+/// Token bucket limiting retries to a share of the request rate, so that an
+/// upstream outage does not multiply the load on it
+struct RetryBudget {
+    ratio: f64,
+    min_per_sec: f64,
+    state: Mutex<BudgetState>,
+}
+
+struct BudgetState {
+    tokens: f64,
+    updated: Instant,
+}
+
+impl RetryBudget {
+    fn new(ratio: f64, min_per_sec: u32) -> Self {
+        let min_per_sec = min_per_sec as f64;
+        Self {
+            ratio,
+            min_per_sec,
+            state: Mutex::new(BudgetState {
+                tokens: min_per_sec,
+                updated: Instant::now(),
+            }),
+        }
+    }
+
+    fn capacity(&self) -> f64 {
+        (self.min_per_sec * BUDGET_WINDOW_SECS).max(MIN_BUDGET_CAPACITY)
+    }
+
+    fn update(&self, f: impl FnOnce(&mut f64) -> bool) -> bool {
+        let mut state = self.state.lock().unwrap_or_else(|e| e.into_inner());
+        let now = Instant::now();
+        let elapsed = now.duration_since(state.updated).as_secs_f64();
+        state.updated = now;
+        state.tokens = (state.tokens + elapsed * self.min_per_sec).min(self.capacity());
+        let result = f(&mut state.tokens);
+        state.tokens = state.tokens.min(self.capacity());
+        result
+    }
+
+    fn deposit(&self) {
+        self.update(|tokens| {
+            *tokens += self.ratio;
+            true
+        });
+    }
+
+    fn withdraw(&self) -> bool {
+        self.update(|tokens| {
+            if *tokens >= 1.0 {
+                *tokens -= 1.0;
+                true
+            } else {
+                false
+            }
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rocket::data::ByteUnit;
+
+    fn config(budget_ratio: f64, budget_min_per_sec: u32) -> RetryConfig {
+        RetryConfig {
+            max_attempts: 3,
+            initial_backoff_ms: 50,
+            max_backoff_ms: 1000,
+            on_errors: vec!["connect".into()],
+            on_statuses: vec![503],
+            methods: vec!["GET".into(), "PUT".into()],
+            max_body_size: ByteUnit::Kibibyte(64),
+            budget_ratio,
+            budget_min_per_sec,
+        }
+    }
+
+    #[test]
+    fn budget_earns_a_fraction_of_a_retry_per_request() {
+        let budget = RetryBudget::new(0.5, 0);
+        assert!(!budget.withdraw());
+        budget.deposit();
+        assert!(!budget.withdraw());
+        budget.deposit();
+        assert!(budget.withdraw());
+        assert!(!budget.withdraw());
+    }
+
+    #[test]
+    fn budget_is_capped() {
+        let budget = RetryBudget::new(1.0, 0);
+        for _ in 0..100 {
+            budget.deposit();
+        }
+        let withdrawn = (0..100).take_while(|_| budget.withdraw()).count();
+        assert_eq!(withdrawn, MIN_BUDGET_CAPACITY as usize);
+    }
+
+    #[test]
+    fn budget_starts_with_the_minimum_rate() {
+        let budget = RetryBudget::new(0.0, 5);
+        let withdrawn = (0..100).take_while(|_| budget.withdraw()).count();
+        assert_eq!(withdrawn, 5);
+    }
+
+    #[test]
+    fn retries_stop_when_the_budget_is_exhausted() {
+        let policy = RetryPolicy::new(&config(0.0, 0));
+        assert!(!policy.should_retry("abc123", 1, RetryReason::Status(503)));
+
+        let policy = RetryPolicy::new(&config(1.0, 0));
+        policy.record_request();
+        assert!(policy.should_retry("abc123", 1, RetryReason::Status(503)));
+        assert!(!policy.should_retry("abc123", 2, RetryReason::Status(503)));
+    }
+
+    #[test]
+    fn only_configured_failures_are_retried() {
+        let policy = RetryPolicy::new(&config(0.0, 100));
+        assert!(policy.should_retry("abc123", 1, RetryReason::Error("connect")));
+        assert!(policy.should_retry("abc123", 2, RetryReason::Status(503)));
+        assert!(!policy.should_retry("abc123", 3, RetryReason::Status(503)));
+        assert!(!policy.should_retry("abc123", 1, RetryReason::Status(500)));
+        assert!(!policy.should_retry("abc123", 1, RetryReason::Error("timeout")));
+    }
+
+    #[test]
+    fn only_idempotent_requests_are_retryable() {
+        let policy = RetryPolicy::new(&config(0.2, 10));
+        assert!(policy.is_retryable("get", false));
+        assert!(!policy.is_retryable("POST", false));
+        assert!(policy.is_retryable("POST", true));
+
+        let mut single = config(0.2, 10);
+        single.max_attempts = 1;
+        assert!(!RetryPolicy::new(&single).is_retryable("GET", true));
+    }
+
+    #[test]
+    fn backoff_is_bounded() {
+        let policy = RetryPolicy::new(&config(0.2, 10));
+        for _ in 0..100 {
+            assert!(policy.backoff(1) <= Duration::from_millis(50));
+            assert!(policy.backoff(2) <= Duration::from_millis(100));
+            assert!(policy.backoff(30) <= Duration::from_millis(1000));
+        }
+    }
+}
@@ -1,24 +1,85 @@
 use anyhow::{bail, Context, Result};
-use ra_tls::traits::CertExt;
-use rocket::figment::providers::Serialized;
 use rocket::http::{Header, Status};
 use rocket::request::{FromRequest, Outcome};
 use rocket::response::Responder;
 use rocket::response::Response;
-use rocket::{get, routes, Request};
-use tracing::{debug, warn};
+use rocket::{get, routes, Request, State};
+use std::sync::{Arc, RwLock};
+use tokio_util::sync::CancellationToken;
+use tracing::{debug, field, info, info_span, warn, Instrument, Span};
+use tracing_opentelemetry::OpenTelemetrySpanExt;
 
-/// Custom responder that returns status with headers
+use crate::config::{rocket_figment, Config};
+use crate::identity::{self, PeerIdentity};
+use crate::metrics;
+use crate::policy::{self, Decision, PolicyRule};
+use crate::ready::{ready_handler, Readiness};
+use crate::stats;
+use crate::supervisor::launch_rocket;
+use crate::telemetry::RemoteContext;
+use crate::tls::ClientChainVerifier;
+
+/// Authorization settings shared by the auth service and the inbound proxy
+pub struct AuthState {
+    /// Swapped as a whole on reload; in-flight checks keep the snapshot they started with
+    current: RwLock<Arc<AuthSettings>>,
+}
+
+impl AuthState {
+    pub fn new(config: &Config) -> Result<Self> {
+        Ok(Self {
+            current: RwLock::new(Arc::new(AuthSettings::load(config)?)),
+        })
+    }
+
+    /// The settings currently in effect
+    pub fn settings(&self) -> Arc<AuthSettings> {
+        self.current
+            .read()
+            .unwrap_or_else(|e| e.into_inner())
+            .clone()
+    }
+
+    /// Atomically switch new requests over to `settings`
+    pub fn replace(&self, settings: AuthSettings) {
+        *self.current.write().unwrap_or_else(|e| e.into_inner()) = Arc::new(settings);
+    }
+}
+
+pub struct AuthSettings {
+    pub policy: Vec<PolicyRule>,
+    pub verifier: ClientChainVerifier,
+    pub headers: Vec<String>,
+    /// Refuse `/auth` calls from peers other than loopback or the unix socket
+    pub loopback_only: bool,
+}
+
+impl AuthSettings {
+    pub fn load(config: &Config) -> Result<Self> {
+        identity::validate_attributes(&config.auth.headers)?;
+        Ok(Self {
+            policy: config.auth.policy.clone(),
+            headers: config.auth.headers.clone(),
+            loopback_only: config.auth.loopback_only,
+            verifier: ClientChainVerifier::load(&config.tls)
+                .context("Failed to load client certificate verifier")?,
+        })
+    }
+}
+
+/// Custom responder that returns status with the peer attribute headers
 pub struct AuthSuccessResponse {
-    app_id: String,
+    headers: Vec<(String, String)>,
 }
 
 impl<'r> Responder<'r, 'static> for AuthSuccessResponse {
     fn respond_to(self, _: &'r rocket::Request<'_>) -> rocket::response::Result<'static> {
-        Response::build()
-            .status(Status::Ok)
-            .header(Header::new("x-dstack-app-id", self.app_id))
-            .ok()
+        let mut response_builder = Response::build();
+        response_builder.status(Status::Ok);
+        for (name, value) in self.headers {
+            response_builder.header(Header::new(name, value));
+        }
+        response_builder.ok()
     }
 }
 
@@ -26,6 +87,8 @@ impl<'r> Responder<'r, 'static> for AuthSuccessResponse {
 pub struct AuthHeaders {
     pub client_cert: Option<String>,
     pub client_verify: Option<String>,
+    pub original_uri: Option<String>,
+    pub original_method: Option<String>,
 }
 
 #[rocket::async_trait]
@@ -36,17 +99,69 @@ impl<'r> FromRequest<'r> for AuthHeaders {
         let headers = request.headers();
         let client_cert = headers.get_one("x-client-cert").map(|s| s.to_string());
         let client_verify = headers.get_one("x-client-verify").map(|s| s.to_string());
+        let original_uri = headers.get_one("x-original-uri").map(|s| s.to_string());
+        let original_method = headers.get_one("x-original-method").map(|s| s.to_string());
 
         Outcome::Success(AuthHeaders {
             client_cert,
             client_verify,
+            original_uri,
+            original_method,
         })
     }
 }
 
+/// Request guard admitting only callers on the same host when `auth.loopback_only`
+/// is set, judged by the connection's peer address
+pub struct LocalCaller;
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for LocalCaller {
+    type Error = ();
+
+    async fn from_request(request: &'r Request<'_>) -> Outcome<Self, Self::Error> {
+        let Some(state) = request.rocket().state::<Arc<AuthState>>() else {
+            return Outcome::Error((Status::InternalServerError, ()));
+        };
+        if !state.settings().loopback_only {
+            return Outcome::Success(LocalCaller);
+        }
+        // `ip_header` is disabled for this service, so this is the socket's peer.
+        // Unix socket peers have no IP; the socket's file permissions restrict them.
+        match request.client_ip() {
+            Some(ip) if !ip.to_canonical().is_loopback() => {
+                warn!("Refused /auth call from non-loopback peer {ip}");
+                Outcome::Error((Status::Forbidden, ()))
+            }
+            _ => Outcome::Success(LocalCaller),
+        }
+    }
+}
+
 /// Auth endpoint for nginx auth_request integration
 #[get("/auth")]
-async fn auth_handler(headers: AuthHeaders) -> Result<AuthSuccessResponse, Status> {
+async fn auth_handler(
+    _caller: LocalCaller,
+    headers: AuthHeaders,
+    trace: RemoteContext,
+    state: &State<Arc<AuthState>>,
+) -> Result<AuthSuccessResponse, Status> {
+    let span = info_span!(
+        "auth",
+        otel.kind = "server",
+        caller_app = field::Empty,
+        decision = field::Empty,
+    );
+    span.set_parent(trace.0);
+    authenticate(&headers, &state.settings())
+        .instrument(span)
+        .await
+}
+
+async fn authenticate(
+    headers: &AuthHeaders,
+    settings: &AuthSettings,
+) -> Result<AuthSuccessResponse, Status> {
     // Extract client certificate from headers (passed by nginx)
     let cert_header = headers.client_cert.as_ref();
     let verify_header = headers.client_verify.as_ref();
@@ -63,31 +178,73 @@ async fn auth_handler(headers: AuthHeaders) -> Result<AuthSuccessResponse, Statu
         warn!("Missing cert header");
         return Err(Status::Unauthorized);
     };
+    // The decision is made for a concrete proxied request, never in isolation
+    let (Some(method), Some(uri)) = (
+        headers.original_method.as_deref(),
+        headers.original_uri.as_deref(),
+    ) else {
+        warn!("Missing original request headers");
+        return Err(Status::Unauthorized);
+    };
     // Parse and verify certificate
-    match parse_and_verify_cert(cert_pem).await {
-        Ok(app_id) => {
-            debug!("Auth successful for app_id: {app_id}");
-            Ok(AuthSuccessResponse { app_id })
-        }
+    let peer = match parse_and_verify_cert(cert_pem, &settings.verifier).await {
+        Ok(peer) => peer,
         Err(e) => {
             warn!("Auth failed: {e}");
-            Err(Status::Unauthorized)
+            return Err(Status::Unauthorized);
+        }
+    };
+    debug!(
+        "Auth successful for app_id: {} ({method} {uri})",
+        peer.app_id
+    );
+    Span::current().record("caller_app", peer.app_id.as_str());
+
+    authorize(&settings.policy, &peer.app_id, method, uri)?;
+    Ok(AuthSuccessResponse {
+        headers: peer.headers(&settings.headers),
+    })
+}
+
+/// Check the caller against the configured policy, logging the decision
+pub(crate) fn authorize(
+    rules: &[PolicyRule],
+    app_id: &str,
+    method: &str,
+    uri: &str,
+) -> Result<(), Status> {
+    match policy::evaluate(rules, app_id, method, uri) {
+        Decision::Unrestricted => {
+            Span::current().record("decision", "allow");
+            metrics::record_auth(app_id, true);
+            Ok(())
+        }
+        Decision::Allowed(index) => {
+            info!("Allowed {method} {uri} for app_id {app_id} by policy rule #{index}");
+            Span::current().record("decision", "allow");
+            metrics::record_auth(app_id, true);
+            Ok(())
+        }
+        Decision::Denied => {
+            warn!("Denied {method} {uri} for app_id {app_id}: no policy rule matched");
+            Span::current().record("decision", "deny");
+            stats::record_denial(app_id, method, uri);
+            metrics::record_auth(app_id, false);
+            Err(Status::Forbidden)
         }
     }
 }
 
-async fn parse_and_verify_cert(cert_pem: &str) -> Result<String> {
+async fn parse_and_verify_cert(
+    cert_pem: &str,
+    verifier: &ClientChainVerifier,
+) -> Result<PeerIdentity> {
     let decoded = urlencoding::decode(cert_pem).context("Failed to decode certificate")?;
     let (_, ca_pem) =
         x509_parser::pem::parse_x509_pem(decoded.as_bytes()).context("Failed to parse ca cert")?;
-    let cert = ca_pem.parse_x509().context("Failed to parse ca cert")?;
-    let Some(app_id_bytes) = cert
-        .get_app_id()
-        .context("Failed to get app_id from client cert")?
-    else {
-        bail!("No app_id found in client cert");
-    };
-    Ok(hex::encode(app_id_bytes))
+    // Do not rely on x-client-verify alone: anyone reaching this port could forge it
+    verifier.verify(&ca_pem.contents)?;
+    PeerIdentity::from_der(&ca_pem.contents).context("Failed to parse client cert")
 }
 
 /// Health check endpoint
@@ -97,21 +254,42 @@ fn health_handler() -> Status {
 }
 
 /// Run auth service with configuration from main figment
-pub(crate) async fn run_auth_service(main_figment: &rocket::figment::Figment) -> Result<()> {
-    // Create Rocket figment for auth service using the auth section
-    let figment = rocket::figment::Figment::new()
-        .merge(rocket::Config::default())
-        .merge(Serialized::defaults(
-            main_figment
-                .find_value("auth")
-                .context("auth section not found")?,
-        ));
-
-    let _rocket = rocket::custom(figment)
-        .mount("/", routes![auth_handler, health_handler])
-        .launch()
-        .await
-        .map_err(|e| anyhow::anyhow!("Rocket launch error: {}", e))?;
+pub(crate) async fn run_auth_service(
+    main_figment: &rocket::figment::Figment,
+    config: &Config,
+    state: Arc<AuthState>,
+    readiness: Arc<Readiness>,
+    shutdown: CancellationToken,
+) -> Result<()> {
+    // Create Rocket figment for auth service using the auth section. The peer
+    // address must come from the connection, never from a forgeable header.
+    let mut figment = rocket_figment(main_figment, "auth")?.merge(("ip_header", false));
+    if let Some(socket) = &config.auth.socket {
+        // Rocket does not replace a socket left behind by a previous run
+        if let Err(e) = fs_err::remove_file(socket) {
+            if e.kind() != std::io::ErrorKind::NotFound {
+                return Err(e).context("Failed to remove stale auth socket");
+            }
+        }
+        figment = figment.merge(("address", format!("unix:{socket}")));
+    } else if config.auth.loopback_only && !config.auth.address.is_loopback() {
+        bail!(
+            "auth.loopback_only is set but auth.address {} is not a loopback address",
+            config.auth.address
+        );
+    }
 
-    Ok(())
+    let rocket = rocket::custom(figment)
+        .manage(state)
+        .manage(readiness)
+        .mount(
+            "/",
+            routes![
+                auth_handler,
+                health_handler,
+                ready_handler,
+                metrics::metrics_handler
+            ],
+        );
+    launch_rocket(rocket, shutdown).await
 }
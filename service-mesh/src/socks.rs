@@ -0,0 +1,177 @@
+use anyhow::{bail, Context, Result};
+use std::net::SocketAddr;
+use std::sync::Arc;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+use tokio_util::sync::CancellationToken;
+use tokio_util::task::TaskTracker;
+use tracing::{debug, info, warn};
+
+use crate::breaker::CircuitOpen;
+use crate::client::{target_from_mesh_name, validate_connection_target, ClientState};
+use crate::config::SocksConfig;
+use crate::supervisor::drain_connections;
+use crate::tunnel::relay;
+
+const SOCKS_VERSION: u8 = 0x05;
+const METHOD_NO_AUTH: u8 = 0x00;
+const METHOD_NONE_ACCEPTABLE: u8 = 0xff;
+const CMD_CONNECT: u8 = 0x01;
+const ATYP_IPV4: u8 = 0x01;
+const ATYP_DOMAIN: u8 = 0x03;
+const ATYP_IPV6: u8 = 0x04;
+
+/// SOCKS5 reply codes (RFC 1928, section 6)
+#[derive(Debug, Clone, Copy)]
+#[repr(u8)]
+enum Reply {
+    Succeeded = 0x00,
+    NotAllowed = 0x02,
+    HostUnreachable = 0x04,
+    ConnectionRefused = 0x05,
+    CommandNotSupported = 0x07,
+    AddressTypeNotSupported = 0x08,
+}
+
+/// Run the SOCKS5 listener that tunnels raw TCP streams to `.mesh` destinations
+pub async fn run_socks_listener(
+    config: &SocksConfig,
+    state: Arc<ClientState>,
+    shutdown: CancellationToken,
+) -> Result<()> {
+    let addr = SocketAddr::new(config.address, config.port);
+    let listener = TcpListener::bind(addr)
+        .await
+        .with_context(|| format!("Failed to bind SOCKS listener on {addr}"))?;
+    info!("SOCKS listener started on {addr}");
+
+    let tracker = TaskTracker::new();
+    loop {
+        let (stream, peer) = tokio::select! {
+            accepted = listener.accept() => accepted.context("Failed to accept SOCKS connection")?,
+            _ = shutdown.cancelled() => break,
+        };
+        let state = state.clone();
+        tracker.spawn(async move {
+            if let Err(e) = handle_connection(stream, &state).await {
+                debug!("SOCKS connection from {peer} failed: {e:#}");
+            }
+        });
+    }
+    drain_connections(tracker, "SOCKS").await;
+    Ok(())
+}
+
+async fn handle_connection(mut stream: TcpStream, state: &ClientState) -> Result<()> {
+    negotiate_method(&mut stream).await?;
+
+    let [version, command, _reserved, address_type] = read_array(&mut stream).await?;
+    if version != SOCKS_VERSION {
+        bail!("Unsupported SOCKS version {version}");
+    }
+    let host = match address_type {
+        ATYP_IPV4 => {
+            read_array::<4>(&mut stream).await?;
+            None
+        }
+        ATYP_IPV6 => {
+            read_array::<16>(&mut stream).await?;
+            None
+        }
+        ATYP_DOMAIN => {
+            let [len] = read_array(&mut stream).await?;
+            let mut name = vec![0; len as usize];
+            stream.read_exact(&mut name).await?;
+            Some(String::from_utf8(name).context("Invalid destination host name")?)
+        }
+        _ => {
+            reply(&mut stream, Reply::AddressTypeNotSupported).await?;
+            bail!("Unsupported address type {address_type}");
+        }
+    };
+    // The port is part of the mesh host name, so DST.PORT is not used
+    read_array::<2>(&mut stream).await?;
+
+    if command != CMD_CONNECT {
+        reply(&mut stream, Reply::CommandNotSupported).await?;
+        bail!("Unsupported SOCKS command {command}");
+    }
+    // Destinations are only ever resolved to mesh targets; IP addresses and other
+    // names would leave the mesh
+    let Some(mut target) = host
+        .as_deref()
+        .and_then(|host| target_from_mesh_name(host, &state.runtime()))
+    else {
+        warn!(
+            "Denied SOCKS connection to non-mesh destination {}",
+            host.as_deref().unwrap_or("<ip address>")
+        );
+        reply(&mut stream, Reply::NotAllowed).await?;
+        return Ok(());
+    };
+    if validate_connection_target(&target).is_err() {
+        reply(&mut stream, Reply::NotAllowed).await?;
+        return Ok(());
+    }
+    let _pick = state.balance(&mut target);
+
+    // Same RA-TLS verification of the peer as proxied HTTP requests
+    let upstream = match state.connect_upstream(&target).await {
+        Ok(upstream) => upstream,
+        Err(err) => {
+            warn!(
+                "Failed to open SOCKS tunnel to app_id '{}': {err:?}",
+                target.app_id
+            );
+            let code = if err.is::<CircuitOpen>() {
+                Reply::ConnectionRefused
+            } else {
+                Reply::HostUnreachable
+            };
+            reply(&mut stream, code).await?;
+            return Ok(());
+        }
+    };
+    reply(&mut stream, Reply::Succeeded).await?;
+    debug!(
+        "SOCKS tunnel established to app_id '{}', port {}",
+        target.app_id, target.port
+    );
+    relay(stream, upstream, &target).await;
+    Ok(())
+}
+
+/// Read the client greeting and select "no authentication", the only method served
+async fn negotiate_method(stream: &mut TcpStream) -> Result<()> {
+    let [version, count] = read_array(stream).await?;
+    if version != SOCKS_VERSION {
+        bail!("Unsupported SOCKS version {version}");
+    }
+    let mut methods = vec![0; count as usize];
+    stream.read_exact(&mut methods).await?;
+    if !methods.contains(&METHOD_NO_AUTH) {
+        stream
+            .write_all(&[SOCKS_VERSION, METHOD_NONE_ACCEPTABLE])
+            .await?;
+        bail!("Client offered no supported authentication method");
+    }
+    stream.write_all(&[SOCKS_VERSION, METHOD_NO_AUTH]).await?;
+    Ok(())
+}
+
+async fn read_array<const N: usize>(stream: &mut TcpStream) -> Result<[u8; N]> {
+    let mut buf = [0; N];
+    stream
+        .read_exact(&mut buf)
+        .await
+        .context("SOCKS client closed the connection")?;
+    Ok(buf)
+}
+
+/// Send a reply; the bound address is not meaningful for a tunnel, so it is all zeros
+async fn reply(stream: &mut TcpStream, code: Reply) -> Result<()> {
+    stream
+        .write_all(&[SOCKS_VERSION, code as u8, 0, ATYP_IPV4, 0, 0, 0, 0, 0, 0])
+        .await?;
+    Ok(())
+}
@@ -0,0 +1,111 @@
+use serde::Serialize;
+use std::collections::{BTreeMap, VecDeque};
+use std::sync::{LazyLock, Mutex};
+
+use crate::certs::unix_now;
+use crate::config::TargetInfo;
+
+/// Number of auth denials kept for the admin API
+const MAX_DENIALS: usize = 100;
+/// Number of targets tracked; the least recently used idle one makes room for a new one
+const MAX_TARGETS: usize = 1024;
+
+/// Live per-target counters, as opposed to the cumulative Prometheus metrics
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct TargetStats {
+    /// Open tunnels and requests waiting for response headers
+    pub active: u64,
+    pub requests: u64,
+    pub errors: u64,
+    pub last_error: Option<String>,
+    pub last_used: i64,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct Denial {
+    pub time: i64,
+    pub app_id: String,
+    pub method: String,
+    pub uri: String,
+}
+
+static TARGETS: LazyLock<Mutex<BTreeMap<String, TargetStats>>> = LazyLock::new(Default::default);
+static DENIALS: LazyLock<Mutex<VecDeque<Denial>>> = LazyLock::new(Default::default);
+
+fn target_key(app_id: &str, port: u16) -> String {
+    format!("{}:{port}", app_id.to_lowercase())
+}
+
+fn update(app_id: &str, port: u16, f: impl FnOnce(&mut TargetStats)) {
+    let mut targets = TARGETS.lock().unwrap_or_else(|e| e.into_inner());
+    let key = target_key(app_id, port);
+    if targets.len() >= MAX_TARGETS && !targets.contains_key(&key) {
+        let oldest = targets
+            .iter()
+            .filter(|(_, stats)| stats.active == 0)
+            .min_by_key(|(_, stats)| stats.last_used)
+            .map(|(key, _)| key.clone());
+        if let Some(oldest) = oldest {
+            targets.remove(&oldest);
+        }
+    }
+    let stats = targets.entry(key).or_default();
+    stats.last_used = unix_now();
+    f(stats);
+}
+
+/// Counts the target as active until dropped
+pub struct ActiveGuard {
+    app_id: String,
+    port: u16,
+}
+
+impl Drop for ActiveGuard {
+    fn drop(&mut self) {
+        update(&self.app_id, self.port, |stats| {
+            stats.active = stats.active.saturating_sub(1)
+        });
+    }
+}
+
+pub fn active(target: &TargetInfo) -> ActiveGuard {
+    update(&target.app_id, target.port, |stats| {
+        stats.active += 1;
+        stats.requests += 1;
+    });
+    ActiveGuard {
+        app_id: target.app_id.clone(),
+        port: target.port,
+    }
+}
+
+pub fn record_error(app_id: &str, port: u16, error: String) {
+    update(app_id, port, |stats| {
+        stats.errors += 1;
+        stats.last_error = Some(error);
+    });
+}
+
+/// Per-target stats keyed by `<app_id>:<port>`
+pub fn targets() -> BTreeMap<String, TargetStats> {
+    TARGETS.lock().unwrap_or_else(|e| e.into_inner()).clone()
+}
+
+pub fn record_denial(app_id: &str, method: &str, uri: &str) {
+    let mut denials = DENIALS.lock().unwrap_or_else(|e| e.into_inner());
+    if denials.len() == MAX_DENIALS {
+        denials.pop_front();
+    }
+    denials.push_back(Denial {
+        time: unix_now(),
+        app_id: app_id.to_string(),
+        method: method.to_string(),
+        uri: uri.to_string(),
+    });
+}
+
+/// Most recent auth denials, newest first
+pub fn denials() -> Vec<Denial> {
+    let denials = DENIALS.lock().unwrap_or_else(|e| e.into_inner());
+    denials.iter().rev().cloned().collect()
+}
@@ -0,0 +1,261 @@
+use anyhow::{anyhow, Context, Result};
+use rocket::{Build, Rocket};
+use serde::Serialize;
+use std::collections::BTreeMap;
+use std::future::Future;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::task::JoinSet;
+use tokio_util::sync::CancellationToken;
+use tokio_util::task::TaskTracker;
+use tracing::{error, info, warn};
+
+const INITIAL_BACKOFF: Duration = Duration::from_secs(1);
+const MAX_BACKOFF: Duration = Duration::from_secs(60);
+/// A service that stayed up this long gets its backoff reset
+const STABLE_AFTER: Duration = Duration::from_secs(60);
+/// How long raw TCP listeners wait for open connections on shutdown
+const DRAIN_TIMEOUT: Duration = Duration::from_secs(30);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ServiceState {
+    Running,
+    Restarting,
+    Stopped,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct ServiceStatus {
+    pub state: ServiceState,
+    pub restarts: u32,
+    pub last_error: Option<String>,
+}
+
+pub type ServiceStatuses = Arc<Mutex<BTreeMap<String, ServiceStatus>>>;
+
+/// Cancellation tokens of the current run of each service
+type RunTokens = Arc<Mutex<BTreeMap<String, CancellationToken>>>;
+
+/// Runs the enabled services, restarting crashed ones with exponential backoff
+pub struct Supervisor {
+    shutdown: CancellationToken,
+    statuses: ServiceStatuses,
+    runs: RunTokens,
+    services: JoinSet<()>,
+}
+
+/// Lets other components gracefully restart supervised services
+#[derive(Clone)]
+pub struct ServiceControl {
+    runs: RunTokens,
+    shutdown: CancellationToken,
+}
+
+impl ServiceControl {
+    /// Gracefully stop the current run of a service and start it again right away,
+    /// e.g. to rebind a listener. Returns false if no such service is running.
+    pub fn restart(&self, name: &str) -> bool {
+        let runs = self.runs.lock().unwrap_or_else(|e| e.into_inner());
+        match runs.get(name) {
+            Some(run) => {
+                run.cancel();
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Shut every service down as if a termination signal had been received
+    pub fn shutdown(&self) {
+        self.shutdown.cancel();
+    }
+}
+
+impl Supervisor {
+    pub fn new() -> Self {
+        Self {
+            shutdown: CancellationToken::new(),
+            statuses: Default::default(),
+            runs: Default::default(),
+            services: JoinSet::new(),
+        }
+    }
+
+    /// Shared view of the per-service state
+    pub fn statuses(&self) -> ServiceStatuses {
+        self.statuses.clone()
+    }
+
+    pub fn control(&self) -> ServiceControl {
+        ServiceControl {
+            runs: self.runs.clone(),
+            shutdown: self.shutdown.clone(),
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.services.is_empty()
+    }
+
+    /// Supervise a service. `service` is called again for every restart and must
+    /// return once the shutdown token it is given is cancelled.
+    pub fn spawn<F, Fut>(&mut self, name: impl Into<String>, service: F)
+    where
+        F: Fn(CancellationToken) -> Fut + Send + 'static,
+        Fut: Future<Output = Result<()>> + Send + 'static,
+    {
+        let name = name.into();
+        let shutdown = self.shutdown.clone();
+        let statuses = self.statuses.clone();
+        let runs = self.runs.clone();
+        self.services.spawn(async move {
+            let mut backoff = INITIAL_BACKOFF;
+            let mut restarts = 0;
+            loop {
+                set_status(&statuses, &name, ServiceState::Running, restarts, None);
+                info!("Service {name} started");
+                let started = Instant::now();
+                let run = shutdown.child_token();
+                runs.lock()
+                    .unwrap_or_else(|e| e.into_inner())
+                    .insert(name.clone(), run.clone());
+
+                // Run on its own task so that a panic is treated like any other failure
+                let result = match tokio::spawn(service(run.clone())).await {
+                    Ok(result) => result,
+                    Err(e) => Err(anyhow!("Service panicked: {e}")),
+                };
+
+                if shutdown.is_cancelled() {
+                    if let Err(err) = result {
+                        warn!("Service {name} failed during shutdown: {err:?}");
+                    }
+                    info!("Service {name} stopped");
+                    set_status(&statuses, &name, ServiceState::Stopped, restarts, None);
+                    return;
+                }
+                if run.is_cancelled() {
+                    if let Err(err) = result {
+                        warn!("Service {name} failed while restarting: {err:?}");
+                    }
+                    info!("Service {name} restarting");
+                    continue;
+                }
+
+                let error = match result {
+                    Ok(()) => "exited unexpectedly".to_string(),
+                    Err(err) => format!("{err:?}"),
+                };
+                if started.elapsed() >= STABLE_AFTER {
+                    backoff = INITIAL_BACKOFF;
+                }
+                restarts += 1;
+                error!("Service {name} failed: {error}; restarting in {backoff:?}");
+                set_status(
+                    &statuses,
+                    &name,
+                    ServiceState::Restarting,
+                    restarts,
+                    Some(error),
+                );
+
+                tokio::select! {
+                    _ = tokio::time::sleep(backoff) => {}
+                    _ = shutdown.cancelled() => {
+                        set_status(&statuses, &name, ServiceState::Stopped, restarts, None);
+                        return;
+                    }
+                }
+                backoff = (backoff * 2).min(MAX_BACKOFF);
+            }
+        });
+    }
+
+    /// Wait for SIGTERM, SIGINT or [`ServiceControl::shutdown`], then shut every
+    /// service down and wait for them to drain
+    pub async fn run(mut self) -> Result<()> {
+        tokio::select! {
+            result = wait_for_signal() => result?,
+            _ = self.shutdown.cancelled() => info!("Shutdown requested"),
+        }
+        info!("Shutting down, draining in-flight requests");
+        self.shutdown.cancel();
+        while let Some(result) = self.services.join_next().await {
+            if let Err(e) = result {
+                error!("Supervisor task failed: {e}");
+            }
+        }
+        info!("All services stopped");
+        Ok(())
+    }
+}
+
+fn set_status(
+    statuses: &ServiceStatuses,
+    name: &str,
+    state: ServiceState,
+    restarts: u32,
+    last_error: Option<String>,
+) {
+    let mut statuses = statuses.lock().unwrap_or_else(|e| e.into_inner());
+    statuses.insert(
+        name.to_string(),
+        ServiceStatus {
+            state,
+            restarts,
+            last_error,
+        },
+    );
+}
+
+async fn wait_for_signal() -> Result<()> {
+    use tokio::signal::unix::{signal, SignalKind};
+    let mut sigterm =
+        signal(SignalKind::terminate()).context("Failed to install SIGTERM handler")?;
+    tokio::select! {
+        _ = sigterm.recv() => info!("Received SIGTERM"),
+        result = tokio::signal::ctrl_c() => {
+            result.context("Failed to listen for SIGINT")?;
+            info!("Received SIGINT");
+        }
+    }
+    Ok(())
+}
+
+/// Launch a Rocket instance and gracefully shut it down once `shutdown` is cancelled.
+///
+/// Rocket drains in-flight requests according to its `shutdown.grace` setting.
+pub async fn launch_rocket(rocket: Rocket<Build>, shutdown: CancellationToken) -> Result<()> {
+    let rocket = rocket
+        .ignite()
+        .await
+        .map_err(|e| anyhow!("Rocket ignite error: {}", e))?;
+    let handle = rocket.shutdown();
+    let notifier = tokio::spawn(async move {
+        shutdown.cancelled().await;
+        handle.notify();
+    });
+    let result = rocket.launch().await;
+    notifier.abort();
+    result.map_err(|e| anyhow!("Rocket launch error: {}", e))?;
+    Ok(())
+}
+
+/// Wait for connections tracked by `tracker` to finish, up to a timeout
+pub async fn drain_connections(tracker: TaskTracker, what: &str) {
+    tracker.close();
+    if tracker.is_empty() {
+        return;
+    }
+    info!("Draining {} {what} connection(s)", tracker.len());
+    if tokio::time::timeout(DRAIN_TIMEOUT, tracker.wait())
+        .await
+        .is_err()
+    {
+        warn!(
+            "Timed out draining {what} connections, {} still open",
+            tracker.len()
+        );
+    }
+}
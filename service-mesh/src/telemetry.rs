@@ -0,0 +1,132 @@
+use anyhow::{Context as _, Result};
+use opentelemetry::trace::TracerProvider as _;
+use opentelemetry::{global, Context};
+use opentelemetry_otlp::{SpanExporter, WithExportConfig};
+use opentelemetry_sdk::propagation::TraceContextPropagator;
+use opentelemetry_sdk::trace::SdkTracerProvider;
+use opentelemetry_sdk::Resource;
+use reqwest::header::{HeaderMap, HeaderName, HeaderValue};
+use rocket::request::{FromRequest, Outcome};
+use rocket::Request;
+use std::collections::HashMap;
+use tracing::Span;
+use tracing_opentelemetry::OpenTelemetrySpanExt;
+use tracing_subscriber::layer::SubscriberExt;
+use tracing_subscriber::util::SubscriberInitExt;
+use tracing_subscriber::EnvFilter;
+
+use crate::config::TelemetryConfig;
+
+/// Headers carrying the W3C trace context
+const TRACE_HEADERS: &[&str] = &["traceparent", "tracestate"];
+
+/// Install the log subscriber and, when enabled, the OTLP span exporter.
+///
+/// The returned provider must be shut down on exit to flush pending spans.
+pub fn init(config: &TelemetryConfig) -> Result<Option<SdkTracerProvider>> {
+    global::set_text_map_propagator(TraceContextPropagator::new());
+
+    let provider = if config.enabled {
+        let exporter = SpanExporter::builder()
+            .with_http()
+            .with_endpoint(&config.otlp_endpoint)
+            .build()
+            .context("Failed to build OTLP exporter")?;
+        Some(
+            SdkTracerProvider::builder()
+                .with_batch_exporter(exporter)
+                .with_resource(
+                    Resource::builder()
+                        .with_service_name(config.service_name.clone())
+                        .build(),
+                )
+                .build(),
+        )
+    } else {
+        None
+    };
+    let otel = provider
+        .as_ref()
+        .map(|provider| tracing_opentelemetry::layer().with_tracer(provider.tracer("dstack-mesh")));
+
+    // JSON output for Datadog
+    tracing_subscriber::registry()
+        .with(EnvFilter::try_from_default_env().unwrap_or_else(|_| EnvFilter::new("info")))
+        .with(tracing_subscriber::fmt::layer().json())
+        .with(otel)
+        .init();
+    Ok(provider)
+}
+
+/// Trace context the caller propagated in `traceparent`/`tracestate`
+pub fn extract_context(headers: &rocket::http::HeaderMap<'_>) -> Context {
+    extract(|name| headers.get_one(name).map(str::to_string))
+}
+
+/// Same as [`extract_context`], for requests served by hyper
+pub fn extract_context_from(headers: &HeaderMap) -> Context {
+    extract(|name| {
+        headers
+            .get(name)
+            .and_then(|value| value.to_str().ok())
+            .map(str::to_string)
+    })
+}
+
+fn extract(header: impl Fn(&str) -> Option<String>) -> Context {
+    let carrier: HashMap<String, String> = TRACE_HEADERS
+        .iter()
+        .filter_map(|name| header(name).map(|value| (name.to_string(), value)))
+        .collect();
+    global::get_text_map_propagator(|propagator| propagator.extract(&carrier))
+}
+
+/// Make the current span the parent of an upstream request.
+///
+/// Replaces any trace headers copied from the caller; without an exporter there is
+/// no span context and the caller's headers are passed through unchanged.
+pub fn inject_context(request_builder: reqwest::RequestBuilder) -> reqwest::RequestBuilder {
+    let headers = current_context_headers();
+    if headers.is_empty() {
+        request_builder
+    } else {
+        request_builder.headers(headers)
+    }
+}
+
+/// Same as [`inject_context`], for requests sent with hyper
+pub fn inject_headers(headers: &mut HeaderMap) {
+    for (name, value) in current_context_headers() {
+        if let Some(name) = name {
+            headers.insert(name, value);
+        }
+    }
+}
+
+fn current_context_headers() -> HeaderMap {
+    let mut carrier = HashMap::new();
+    global::get_text_map_propagator(|propagator| {
+        propagator.inject_context(&Span::current().context(), &mut carrier)
+    });
+    carrier
+        .into_iter()
+        .filter_map(|(name, value)| {
+            Some((
+                HeaderName::try_from(name).ok()?,
+                HeaderValue::try_from(value).ok()?,
+            ))
+        })
+        .collect()
+}
+
+/// Request guard for the caller's trace context
+pub struct RemoteContext(pub Context);
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for RemoteContext {
+    type Error = ();
+
+    async fn from_request(request: &'r Request<'_>) -> Outcome<Self, Self::Error> {
+        Outcome::Success(RemoteContext(extract_context(request.headers())))
+    }
+}
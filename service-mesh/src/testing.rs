@@ -0,0 +1,91 @@
+//! Certificates and configuration for tests that run the proxies end to end
+
+use rcgen::{
+    BasicConstraints, CertificateParams, CustomExtension, ExtendedKeyUsagePurpose, IsCa, KeyPair,
+    KeyUsagePurpose,
+};
+use std::time::Duration;
+use tempfile::TempDir;
+use tokio::net::TcpStream;
+
+use crate::config::{Config, LoadedConfig};
+
+/// RA-TLS extension carrying the app_id (`ra_tls::oids::PHALA_RATLS_APP_ID`)
+const APP_ID_OID: &[u64] = &[1, 3, 6, 1, 4, 1, 62397, 1, 3];
+
+/// app_id of the test certificate
+pub const APP_ID: &str = "1111111111111111111111111111111111111111";
+
+/// A CA and an RA-TLS certificate for [`APP_ID`] signed by it, written to a temporary
+/// directory. The certificate serves both as client and as server certificate.
+pub struct TestMesh {
+    dir: TempDir,
+}
+
+impl TestMesh {
+    pub fn new() -> Self {
+        let dir = tempfile::tempdir().unwrap();
+
+        let ca_key = KeyPair::generate().unwrap();
+        let mut params = CertificateParams::new(Vec::<String>::new()).unwrap();
+        params.is_ca = IsCa::Ca(BasicConstraints::Unconstrained);
+        params.key_usages = vec![KeyUsagePurpose::KeyCertSign, KeyUsagePurpose::CrlSign];
+        let ca = params.self_signed(&ca_key).unwrap();
+
+        let key = KeyPair::generate().unwrap();
+        let mut params = CertificateParams::new(vec!["localhost".to_string()]).unwrap();
+        params.extended_key_usages = vec![
+            ExtendedKeyUsagePurpose::ServerAuth,
+            ExtendedKeyUsagePurpose::ClientAuth,
+        ];
+        // The extension holds the app_id as a DER OCTET STRING
+        let app_id = hex::decode(APP_ID).unwrap();
+        let mut content = vec![0x04, app_id.len() as u8];
+        content.extend_from_slice(&app_id);
+        params
+            .custom_extensions
+            .push(CustomExtension::from_oid_content(APP_ID_OID, content));
+        let cert = params.signed_by(&key, &ca, &ca_key).unwrap();
+
+        std::fs::write(dir.path().join("ca.crt"), ca.pem()).unwrap();
+        std::fs::write(dir.path().join("app.crt"), cert.pem()).unwrap();
+        std::fs::write(dir.path().join("app.key"), key.serialize_pem()).unwrap();
+        Self { dir }
+    }
+
+    /// The default configuration with the test certificates and `overrides`, a TOML
+    /// fragment that must not contain a `[tls]` table, on top
+    pub fn config(&self, overrides: &str) -> Config {
+        let dir = self.dir.path().display();
+        let path = self.dir.path().join("dstack-mesh.toml");
+        let toml = format!(
+            "[tls]\n\
+             cert_file = \"{dir}/app.crt\"\n\
+             key_file = \"{dir}/app.key\"\n\
+             ca_file = \"{dir}/ca.crt\"\n\
+             {overrides}"
+        );
+        std::fs::write(&path, toml).unwrap();
+        LoadedConfig::load(path.to_str()).unwrap().config
+    }
+}
+
+/// A port that was free a moment ago, for listeners that bind by configuration
+pub fn free_port() -> u16 {
+    std::net::TcpListener::bind("127.0.0.1:0")
+        .unwrap()
+        .local_addr()
+        .unwrap()
+        .port()
+}
+
+/// Wait until a listener started in the background accepts connections
+pub async fn wait_for_port(port: u16) {
+    for _ in 0..100 {
+        if TcpStream::connect(("127.0.0.1", port)).await.is_ok() {
+            return;
+        }
+        tokio::time::sleep(Duration::from_millis(20)).await;
+    }
+    panic!("Nothing is listening on port {port}");
+}
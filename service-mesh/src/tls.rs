@@ -0,0 +1,409 @@
+use anyhow::{bail, Context, Result};
+use rustls::client::danger::{HandshakeSignatureValid, ServerCertVerified, ServerCertVerifier};
+use rustls::client::WebPkiServerVerifier;
+use rustls::crypto::{ring, CryptoProvider};
+use rustls::pki_types::pem::PemObject;
+use rustls::pki_types::{CertificateDer, PrivateKeyDer, ServerName, UnixTime};
+use rustls::server::danger::ClientCertVerifier;
+use rustls::server::{ClientHello, ResolvesServerCert, WebPkiClientVerifier};
+use rustls::sign::CertifiedKey;
+use rustls::{
+    CertificateError, ClientConfig, DigitallySignedStruct, Error as TlsError, RootCertStore,
+    ServerConfig, SignatureScheme,
+};
+use std::sync::{Arc, RwLock};
+use tracing::warn;
+
+use crate::config::TlsConfig;
+use crate::identity::PeerIdentity;
+use crate::metrics;
+use crate::policy::PeerPolicy;
+
+/// Our own mTLS identity and the trust anchors loaded from the `[tls]` section
+pub struct TlsMaterial {
+    cert_chain: Vec<CertificateDer<'static>>,
+    key: PrivateKeyDer<'static>,
+    roots: Arc<RootCertStore>,
+    provider: Arc<CryptoProvider>,
+}
+
+impl TlsMaterial {
+    /// Load certificate chain, private key and CA from the configured files
+    pub fn load(config: &TlsConfig) -> Result<Self> {
+        use fs_err as fs;
+        let key_pem = fs::read(&config.key_file).context("Failed to read key file")?;
+        let cert_pem = fs::read(&config.cert_file).context("Failed to read cert file")?;
+        let ca_pem = fs::read(&config.ca_file).context("Failed to read CA file")?;
+        // The files are replaced one by one, so they may be seen halfway through
+        Self::from_pem(&key_pem, &cert_pem, &ca_pem)
+    }
+
+    /// Parse a key, certificate chain and CA held in memory, checking that the key
+    /// belongs to the certificate
+    pub fn from_pem(key_pem: &[u8], cert_pem: &[u8], ca_pem: &[u8]) -> Result<Self> {
+        let cert_chain = CertificateDer::pem_slice_iter(cert_pem)
+            .collect::<Result<Vec<_>, _>>()
+            .context("Failed to parse cert file")?;
+        let key = PrivateKeyDer::from_pem_slice(key_pem).context("Failed to parse key file")?;
+
+        let material = Self {
+            cert_chain,
+            key,
+            roots: Arc::new(parse_roots(ca_pem)?),
+            provider: Arc::new(ring::default_provider()),
+        };
+        material
+            .certified_key()?
+            .keys_match()
+            .context("Private key does not match the certificate")?;
+        Ok(material)
+    }
+
+    /// Build a rustls client config that presents our identity and only accepts
+    /// peers whose RA-TLS certificate carries `expected_app_id` and satisfies `policy`
+    pub fn client_config(
+        &self,
+        expected_app_id: &str,
+        policy: Option<&PeerPolicy>,
+    ) -> Result<ClientConfig> {
+        let verifier = AppIdVerifier::new(self, expected_app_id, policy)?;
+        let config = ClientConfig::builder_with_provider(self.provider.clone())
+            .with_safe_default_protocol_versions()
+            .context("Failed to select TLS protocol versions")?
+            .dangerous()
+            .with_custom_certificate_verifier(Arc::new(verifier))
+            .with_client_auth_cert(self.cert_chain.clone(), self.key.clone_key())
+            .context("Failed to set client certificate")?;
+        Ok(config)
+    }
+
+    /// Our certificate chain and signing key, as served by a [`CertResolver`]
+    pub fn certified_key(&self) -> Result<CertifiedKey> {
+        let key = self
+            .provider
+            .key_provider
+            .load_private_key(self.key.clone_key())
+            .context("Unsupported private key")?;
+        Ok(CertifiedKey::new(self.cert_chain.clone(), key))
+    }
+
+    /// Build a rustls server config that presents the certificate from `resolver` and
+    /// requires client certificates chained to the CA, offering `alpn_protocols`
+    pub fn server_config(
+        &self,
+        resolver: Arc<CertResolver>,
+        alpn_protocols: &[&[u8]],
+    ) -> Result<ServerConfig> {
+        let verifier =
+            WebPkiClientVerifier::builder_with_provider(self.roots.clone(), self.provider.clone())
+                .build()
+                .context("Failed to build client certificate verifier")?;
+        let mut config = ServerConfig::builder_with_provider(self.provider.clone())
+            .with_safe_default_protocol_versions()
+            .context("Failed to select TLS protocol versions")?
+            .with_client_cert_verifier(verifier)
+            .with_cert_resolver(resolver);
+        config.alpn_protocols = alpn_protocols.iter().map(|p| p.to_vec()).collect();
+        Ok(config)
+    }
+
+    /// Chain-validate our own certificate against the configured CA
+    pub fn verify_own_chain(&self) -> Result<()> {
+        let verifier =
+            WebPkiServerVerifier::builder_with_provider(self.roots.clone(), self.provider.clone())
+                .build()
+                .context("Failed to build certificate verifier")?;
+        let (leaf, intermediates) = self
+            .cert_chain
+            .split_first()
+            .context("Empty certificate chain")?;
+        let name = ServerName::try_from("localhost").context("Invalid server name")?;
+        match verifier.verify_server_cert(leaf, intermediates, &name, &[], UnixTime::now()) {
+            // Peers identify us by app_id, so the name does not matter
+            Ok(_)
+            | Err(TlsError::InvalidCertificate(
+                CertificateError::NotValidForName | CertificateError::NotValidForNameContext { .. },
+            )) => Ok(()),
+            Err(e) => Err(e).context("Certificate does not chain to the configured CA"),
+        }
+    }
+}
+
+/// Serves the certificate installed last, so renewed certificates are used for new
+/// handshakes without restarting the listener
+#[derive(Debug, Default)]
+pub struct CertResolver {
+    current: RwLock<Option<Arc<CertifiedKey>>>,
+}
+
+impl CertResolver {
+    pub fn replace(&self, key: CertifiedKey) {
+        *self.current.write().unwrap_or_else(|e| e.into_inner()) = Some(Arc::new(key));
+    }
+}
+
+impl ResolvesServerCert for CertResolver {
+    fn resolve(&self, _client_hello: ClientHello<'_>) -> Option<Arc<CertifiedKey>> {
+        self.current
+            .read()
+            .unwrap_or_else(|e| e.into_inner())
+            .clone()
+    }
+}
+
+/// Client config for probing that a TLS endpoint is reachable.
+///
+/// The server certificate is not authenticated; never send data over such a connection.
+pub fn probe_client_config() -> Result<ClientConfig> {
+    let provider = Arc::new(ring::default_provider());
+    let config = ClientConfig::builder_with_provider(provider.clone())
+        .with_safe_default_protocol_versions()
+        .context("Failed to select TLS protocol versions")?
+        .dangerous()
+        .with_custom_certificate_verifier(Arc::new(ProbeVerifier { provider }))
+        .with_no_client_auth();
+    Ok(config)
+}
+
+fn parse_roots(ca_pem: &[u8]) -> Result<RootCertStore> {
+    let mut roots = RootCertStore::empty();
+    for ca in CertificateDer::pem_slice_iter(ca_pem) {
+        roots
+            .add(ca.context("Failed to parse CA file")?)
+            .context("Failed to add CA certificate")?;
+    }
+    Ok(roots)
+}
+
+/// Verifies client certificates handed to us out of band (e.g. by nginx) against the
+/// mesh CA, including validity periods and client-auth usage
+pub struct ClientChainVerifier {
+    inner: Arc<dyn ClientCertVerifier>,
+    /// nginx only forwards the leaf, so intermediate CAs have to be supplied here
+    intermediates: Vec<CertificateDer<'static>>,
+}
+
+impl ClientChainVerifier {
+    /// Trust `tls.ca_file`. Intermediates are taken from `ca_file` and from the chain
+    /// in `cert_file`, which peers issued by the same CA share.
+    pub fn load(config: &TlsConfig) -> Result<Self> {
+        let ca_pem = fs_err::read(&config.ca_file).context("Failed to read CA file")?;
+        let cert_pem = fs_err::read(&config.cert_file).context("Failed to read cert file")?;
+        let inner = WebPkiClientVerifier::builder_with_provider(
+            Arc::new(parse_roots(&ca_pem)?),
+            Arc::new(ring::default_provider()),
+        )
+        .build()
+        .context("Failed to build client certificate verifier")?;
+
+        let mut intermediates = CertificateDer::pem_slice_iter(&ca_pem)
+            .collect::<Result<Vec<_>, _>>()
+            .context("Failed to parse CA file")?;
+        let chain = CertificateDer::pem_slice_iter(&cert_pem)
+            .collect::<Result<Vec<_>, _>>()
+            .context("Failed to parse cert file")?;
+        intermediates.extend(chain.into_iter().skip(1));
+        Ok(Self {
+            inner,
+            intermediates,
+        })
+    }
+
+    /// Chain-validate a DER-encoded end-entity certificate at the current time
+    pub fn verify(&self, end_entity: &[u8]) -> Result<()> {
+        self.inner
+            .verify_client_cert(
+                &CertificateDer::from(end_entity),
+                &self.intermediates,
+                UnixTime::now(),
+            )
+            .context("Client certificate verification failed")?;
+        Ok(())
+    }
+}
+
+/// Check a peer's RA-TLS certificate against the expected app_id and measurement policy
+pub fn verify_peer(
+    der: &[u8],
+    expected_app_id: &str,
+    policy: Option<&PeerPolicy>,
+) -> Result<PeerIdentity> {
+    let peer = PeerIdentity::from_der(der)?;
+    if !peer.app_id.eq_ignore_ascii_case(expected_app_id) {
+        bail!(
+            "Server app_id mismatch: expected '{}', got '{}'",
+            expected_app_id,
+            peer.app_id
+        );
+    }
+    if let Some(policy) = policy {
+        policy
+            .check(&peer)
+            .context("Peer does not satisfy measurement policy")?;
+    }
+    Ok(peer)
+}
+
+/// Accepts any server certificate, checking only the handshake signatures
+#[derive(Debug)]
+struct ProbeVerifier {
+    provider: Arc<CryptoProvider>,
+}
+
+impl ServerCertVerifier for ProbeVerifier {
+    fn verify_server_cert(
+        &self,
+        _end_entity: &CertificateDer<'_>,
+        _intermediates: &[CertificateDer<'_>],
+        _server_name: &ServerName<'_>,
+        _ocsp_response: &[u8],
+        _now: UnixTime,
+    ) -> Result<ServerCertVerified, TlsError> {
+        Ok(ServerCertVerified::assertion())
+    }
+
+    fn verify_tls12_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, TlsError> {
+        rustls::crypto::verify_tls12_signature(
+            message,
+            cert,
+            dss,
+            &self.provider.signature_verification_algorithms,
+        )
+    }
+
+    fn verify_tls13_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, TlsError> {
+        rustls::crypto::verify_tls13_signature(
+            message,
+            cert,
+            dss,
+            &self.provider.signature_verification_algorithms,
+        )
+    }
+
+    fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
+        self.provider
+            .signature_verification_algorithms
+            .supported_schemes()
+    }
+}
+
+/// Server certificate verifier that chain-validates against the mesh CA and then
+/// checks the app_id and measurements embedded in the peer's RA-TLS certificate.
+///
+/// Hostnames are not checked: requests are addressed through the gateway, so the
+/// app_id is what identifies the peer.
+#[derive(Debug)]
+pub struct AppIdVerifier {
+    inner: Arc<WebPkiServerVerifier>,
+    expected_app_id: String,
+    policy: Option<PeerPolicy>,
+}
+
+impl AppIdVerifier {
+    fn new(
+        material: &TlsMaterial,
+        expected_app_id: &str,
+        policy: Option<&PeerPolicy>,
+    ) -> Result<Self> {
+        let inner = WebPkiServerVerifier::builder_with_provider(
+            material.roots.clone(),
+            material.provider.clone(),
+        )
+        .build()
+        .context("Failed to build certificate verifier")?;
+        Ok(Self {
+            inner,
+            expected_app_id: expected_app_id.to_lowercase(),
+            policy: policy.cloned(),
+        })
+    }
+}
+
+impl ServerCertVerifier for AppIdVerifier {
+    fn verify_server_cert(
+        &self,
+        end_entity: &CertificateDer<'_>,
+        intermediates: &[CertificateDer<'_>],
+        server_name: &ServerName<'_>,
+        ocsp_response: &[u8],
+        now: UnixTime,
+    ) -> Result<ServerCertVerified, TlsError> {
+        match self.inner.verify_server_cert(
+            end_entity,
+            intermediates,
+            server_name,
+            ocsp_response,
+            now,
+        ) {
+            // The chain is validated before the name, so a name mismatch means the chain is good
+            Ok(_)
+            | Err(TlsError::InvalidCertificate(
+                CertificateError::NotValidForName | CertificateError::NotValidForNameContext { .. },
+            )) => {}
+            Err(e) => return Err(e),
+        }
+
+        let peer = match verify_peer(end_entity, &self.expected_app_id, self.policy.as_ref()) {
+            Ok(peer) => peer,
+            Err(err) => {
+                warn!("Rejecting TLS handshake: {err:?}");
+                return Err(TlsError::InvalidCertificate(
+                    CertificateError::ApplicationVerificationFailure,
+                ));
+            }
+        };
+        metrics::mark_verified(&self.expected_app_id, peer.instance_id.as_deref());
+        Ok(ServerCertVerified::assertion())
+    }
+
+    fn verify_tls12_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, TlsError> {
+        self.inner.verify_tls12_signature(message, cert, dss)
+    }
+
+    fn verify_tls13_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, TlsError> {
+        self.inner.verify_tls13_signature(message, cert, dss)
+    }
+
+    fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
+        self.inner.supported_verify_schemes()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::TestMesh;
+
+    #[test]
+    fn rejects_key_that_does_not_match_certificate() {
+        let mesh = TestMesh::new();
+        let config = mesh.config("");
+        assert!(TlsMaterial::load(&config.tls).is_ok());
+
+        // As seen between replacing the key and the certificate
+        let other = rcgen::KeyPair::generate().unwrap();
+        std::fs::write(&config.tls.key_file, other.serialize_pem()).unwrap();
+        let err = TlsMaterial::load(&config.tls).err().unwrap();
+        assert!(format!("{err:#}").contains("does not match the certificate"));
+    }
+}
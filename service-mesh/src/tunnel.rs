@@ -0,0 +1,193 @@
+use anyhow::{Context, Result};
+use bytes::Bytes;
+use http_body_util::Empty;
+use hyper::body::Incoming;
+use hyper::server::conn::http1;
+use hyper::service::service_fn;
+use hyper::{Method, Request, Response, StatusCode};
+use hyper_util::rt::TokioIo;
+use std::convert::Infallible;
+use std::sync::Arc;
+use tokio::net::TcpListener;
+use tokio_util::sync::CancellationToken;
+use tokio_util::task::TaskTracker;
+use tracing::{debug, info, warn};
+
+use crate::client::{
+    service_from_host, target_from_dstack_host, target_from_headers, target_from_mesh_name,
+    validate_connection_target, ClientState,
+};
+use crate::config::{ForwardConfig, TargetInfo};
+use crate::metrics;
+use crate::stats;
+use crate::supervisor::drain_connections;
+
+/// Serve `CONNECT` requests on a client proxy connection, spawning the tunnels on
+/// `relays`
+pub(crate) async fn serve_connect<I>(
+    io: I,
+    state: Arc<ClientState>,
+    relays: TaskTracker,
+) -> hyper::Result<()>
+where
+    I: tokio::io::AsyncRead + tokio::io::AsyncWrite + Unpin + Send + 'static,
+{
+    let service = service_fn(move |req| handle_connect(req, state.clone(), relays.clone()));
+    http1::Builder::new()
+        .serve_connection(TokioIo::new(io), service)
+        .with_upgrades()
+        .await
+}
+
+/// Accept TCP connections on `forward.listen` and tunnel each one to the forward's target
+pub async fn run_port_forward(
+    forward: &ForwardConfig,
+    state: Arc<ClientState>,
+    shutdown: CancellationToken,
+) -> Result<()> {
+    let target = forward.target();
+    validate_connection_target(&target)
+        .map_err(|_| anyhow::anyhow!("Invalid forward target for {}", forward.listen))?;
+    let listener = TcpListener::bind(forward.listen)
+        .await
+        .with_context(|| format!("Failed to bind port forward on {}", forward.listen))?;
+    info!(
+        "Forwarding {} to app_id '{}', port {}",
+        forward.listen, target.app_id, target.port
+    );
+
+    let tracker = TaskTracker::new();
+    loop {
+        let (stream, peer) = tokio::select! {
+            accepted = listener.accept() => {
+                accepted.context("Failed to accept forwarded connection")?
+            }
+            _ = shutdown.cancelled() => break,
+        };
+        let state = state.clone();
+        let mut target = target.clone();
+        tracker.spawn(async move {
+            let _pick = state.balance(&mut target);
+            match state.connect_upstream(&target).await {
+                Ok(upstream) => relay(stream, upstream, &target).await,
+                Err(err) => warn!(
+                    "Failed to forward connection from {peer} to app_id '{}': {err:?}",
+                    target.app_id
+                ),
+            }
+        });
+    }
+    drain_connections(tracker, "forwarded").await;
+    Ok(())
+}
+
+async fn handle_connect(
+    req: Request<Incoming>,
+    state: Arc<ClientState>,
+    relays: TaskTracker,
+) -> Result<Response<Empty<Bytes>>, Infallible> {
+    if req.method() != Method::CONNECT {
+        return Ok(status_response(StatusCode::METHOD_NOT_ALLOWED));
+    }
+    let Some(mut target) = connect_target(&req, &state) else {
+        warn!("Denied CONNECT to non-mesh destination {}", req.uri());
+        return Ok(status_response(StatusCode::FORBIDDEN));
+    };
+    if validate_connection_target(&target).is_err() {
+        return Ok(status_response(StatusCode::BAD_REQUEST));
+    }
+    let pick = state.balance(&mut target);
+
+    let upstream = match state.connect_upstream(&target).await {
+        Ok(upstream) => upstream,
+        Err(err) => {
+            warn!(
+                "Failed to open tunnel to app_id '{}': {err:?}",
+                target.app_id
+            );
+            return Ok(status_response(StatusCode::BAD_GATEWAY));
+        }
+    };
+    debug!(
+        "Tunnel established to app_id '{}', port {}",
+        target.app_id, target.port
+    );
+
+    relays.spawn(async move {
+        let _pick = pick;
+        match hyper::upgrade::on(req).await {
+            Ok(upgraded) => relay(TokioIo::new(upgraded), upstream, &target).await,
+            Err(e) => warn!("CONNECT upgrade failed: {e}"),
+        }
+    });
+    Ok(Response::new(Empty::new()))
+}
+
+/// Copy bytes in both directions until either side closes
+pub(crate) async fn relay<A, B>(mut downstream: A, mut upstream: B, target: &TargetInfo)
+where
+    A: tokio::io::AsyncRead + tokio::io::AsyncWrite + Unpin,
+    B: tokio::io::AsyncRead + tokio::io::AsyncWrite + Unpin,
+{
+    let _active = stats::active(target);
+    match tokio::io::copy_bidirectional(&mut downstream, &mut upstream).await {
+        Ok((sent, received)) => {
+            metrics::record_bytes(sent, received);
+            debug!(
+                "Tunnel to app_id '{}' closed: sent {sent} bytes, received {received} bytes",
+                target.app_id
+            )
+        }
+        Err(e) => debug!("Tunnel to app_id '{}' failed: {e}", target.app_id),
+    }
+}
+
+/// Resolve the CONNECT target from the `x-dstack-target-*` headers, a service alias
+/// or the request authority: `<name>.mesh` or `<app_id>-<port>.mesh` like plain
+/// requests, `<app_id>-<port>.dstack` in forward-proxy mode, and `<app_id>-<port>`
+/// otherwise
+fn connect_target(req: &Request<Incoming>, state: &ClientState) -> Option<TargetInfo> {
+    let headers = req.headers();
+    let header = |name: &str| headers.get(name).and_then(|v| v.to_str().ok());
+    if let Some(target) = target_from_headers(
+        header("x-dstack-target-app"),
+        header("x-dstack-target-port"),
+        header("x-dstack-target-instance"),
+    ) {
+        return Some(target);
+    }
+
+    let runtime = state.runtime();
+    if let Some(name) = header("x-dstack-target-service") {
+        let target = runtime.service(name).map(|service| service.target());
+        if target.is_none() {
+            warn!("CONNECT to unknown mesh service '{name}'");
+        }
+        return target;
+    }
+
+    let host = req.uri().host()?;
+    if let Some(name) = service_from_host(host) {
+        let target = target_from_mesh_name(host, &runtime);
+        if target.is_none() {
+            warn!("CONNECT to unknown mesh service '{name}'");
+        }
+        return target;
+    }
+    // Same destinations as plain requests: in forward-proxy mode only mesh names
+    if runtime.forward_proxy().enabled {
+        return target_from_dstack_host(host);
+    }
+    let (app_id, port) = host.rsplit_once('-')?;
+    Some(TargetInfo {
+        app_id: app_id.to_string(),
+        instance_id: String::new(),
+        port: port.parse().ok()?,
+    })
+}
+
+fn status_response(status: StatusCode) -> Response<Empty<Bytes>> {
+    let mut response = Response::new(Empty::new());
+    *response.status_mut() = status;
+    response
+}